@@ -4,30 +4,126 @@ use std::error::Error;
 use std::fmt;
 use std::fmt::{Debug, Formatter};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use serde::{Deserialize, Serialize};
 use crate::parse::REGEXES;
+use serde::{Deserialize, Serialize};
 
 pub type VelocityValue = i16;
 pub type FunctionNum = u8;
 pub type Timestamp = u64;
 pub type TimeScale = f32;
 
+/// The highest function number JMRI supports, i.e. F0 through F68.
+pub const MAX_FUNCTION: FunctionNum = 68;
+
+/// Identifies one of the multi-throttles sharing a WiThrottle connection, e.g. the `T` in `MT`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ThrottleId(char);
+
+impl ThrottleId {
+    const POOL: &'static str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+    pub fn new(id: char) -> Option<Self> {
+        if id.is_ascii_alphanumeric() {
+            Some(ThrottleId(id))
+        } else {
+            None
+        }
+    }
+
+    /// Every id the server may hand out to its sessions.
+    pub fn pool() -> impl Iterator<Item = ThrottleId> {
+        Self::POOL.chars().map(ThrottleId)
+    }
+}
+
+impl Default for ThrottleId {
+    fn default() -> Self {
+        ThrottleId('T')
+    }
+}
+
+impl Display for ThrottleId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct DccTime {
     pub timestamp: Timestamp,
     pub scale: TimeScale,
+    /// When `timestamp` was last set, used to keep the clock ticking between updates
+    #[serde(skip, default = "Instant::now")]
+    updated_at: Instant,
 }
 
 impl DccTime {
     pub fn new(timestamp: Timestamp, scale: TimeScale) -> Self {
-        DccTime { timestamp, scale }
+        DccTime {
+            timestamp,
+            scale,
+            updated_at: Instant::now(),
+        }
     }
 
-    pub fn update(&mut self, timestamp: u64, scale: f32) {
+    /// Sets the time and scale, returning an event if the clock was paused or resumed.
+    pub fn update(&mut self, timestamp: u64, scale: f32) -> Option<ClockEvent> {
+        let was_paused = self.is_paused();
+
         self.timestamp = timestamp;
         self.scale = scale;
+        self.updated_at = Instant::now();
+
+        match (was_paused, self.is_paused()) {
+            (false, true) => Some(ClockEvent::Paused(timestamp)),
+            (true, false) => Some(ClockEvent::Resumed { timestamp, scale }),
+            _ => None,
+        }
+    }
+
+    /// A scale of zero stops the fast clock.
+    pub fn is_paused(&self) -> bool {
+        self.scale <= 0.0
     }
+
+    /// The current layout time in seconds since the epoch, advanced from the last
+    /// update by the wall-clock time since, multiplied by the scale.
+    pub fn now(&self) -> Timestamp {
+        self.now_f64() as Timestamp
+    }
+
+    /// Wall-clock time until the layout clock reaches its next whole minute, along with
+    /// that minute's timestamp. `None` while paused, or when the scale is so small the wait
+    /// can't be represented, which amounts to the same thing.
+    pub fn until_next_minute(&self) -> Option<(Duration, Timestamp)> {
+        if self.is_paused() {
+            return None;
+        }
+
+        let now = self.now_f64();
+        let next = ((now / 60.0).floor() + 1.0) * 60.0;
+        let wait = Duration::try_from_secs_f64((next - now) / self.scale as f64).ok()?;
+        Some((wait, next as Timestamp))
+    }
+
+    fn now_f64(&self) -> f64 {
+        let elapsed = self.updated_at.elapsed().as_secs_f64() * self.scale.max(0.0) as f64;
+        self.timestamp as f64 + elapsed
+    }
+}
+
+/// Fast clock events generated by the server between JMRI's own clock updates.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ClockEvent {
+    /// The layout clock reached a new minute.
+    Tick(Timestamp),
+    Paused(Timestamp),
+    Resumed {
+        timestamp: Timestamp,
+        scale: TimeScale,
+    },
 }
 
 impl Default for DccTime {
@@ -36,7 +132,213 @@ impl Default for DccTime {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressLength {
+    Short,
+    Long,
+}
+
+impl Display for AddressLength {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Short => "S",
+            Self::Long => "L",
+        };
+        f.write_str(s)
+    }
+}
+
+impl FromStr for AddressLength {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "S" => Ok(AddressLength::Short),
+            "L" => Ok(AddressLength::Long),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RosterEntry {
+    pub name: String,
+    pub address: u16,
+    pub length: AddressLength,
+}
+
+impl RosterEntry {
+    /// The address as used in throttle commands, e.g. `L4012`.
+    pub fn dcc_address(&self) -> String {
+        format!("{}{}", self.length, self.address)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Roster {
+    pub entries: Vec<RosterEntry>,
+}
+
+/// A locomotive in a consist.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConsistMember {
+    pub address: String,
+    /// Whether the locomotive faces the opposite way to the lead
+    pub reversed: bool,
+}
+
+/// Locomotives run together as a single unit, led by the first member.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Consist {
+    /// The consist's own address, or the lead's for an ad-hoc consist
+    pub address: String,
+    pub name: String,
+    pub members: Vec<ConsistMember>,
+}
+
+impl Consist {
+    pub fn new(address: String, name: String) -> Self {
+        Consist {
+            address,
+            name,
+            members: Vec::new(),
+        }
+    }
+
+    pub fn lead(&self) -> Option<&ConsistMember> {
+        self.members.first()
+    }
+
+    pub fn member(&self, address: &str) -> Option<&ConsistMember> {
+        self.members.iter().find(|member| member.address == address)
+    }
+
+    /// Adds a member, or updates its orientation if it is already in the consist.
+    pub fn add(&mut self, address: String, reversed: bool) {
+        match self
+            .members
+            .iter_mut()
+            .find(|member| member.address == address)
+        {
+            Some(member) => member.reversed = reversed,
+            None => self.members.push(ConsistMember { address, reversed }),
+        }
+    }
+
+    /// Removes a member, handing an ad-hoc consist's address on to the new lead.
+    pub fn remove(&mut self, address: &str) {
+        self.members.retain(|member| member.address != address);
+        if self.address == address {
+            if let Some(lead) = self.members.first() {
+                self.address = lead.address.clone();
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+}
+
+/// Turnout states, using JMRI's numeric codes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TurnoutState {
+    Unknown,
+    Closed,
+    Thrown,
+    Inconsistent,
+}
+
+impl FromStr for TurnoutState {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "2" => TurnoutState::Closed,
+            "4" => TurnoutState::Thrown,
+            "8" => TurnoutState::Inconsistent,
+            _ => TurnoutState::Unknown,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Turnout {
+    /// JMRI system name, e.g. `LT12`
+    pub name: String,
+    pub user_name: String,
+    pub state: TurnoutState,
+}
+
+/// How JMRI titles turnouts and their states for display, from `PTT`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TurnoutTitles {
+    /// e.g. `Turnouts`
+    pub plural: String,
+    /// e.g. `Turnout`
+    pub singular: String,
+    pub states: Vec<TurnoutStateTitle>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TurnoutStateTitle {
+    pub state: TurnoutState,
+    /// e.g. `Thrown`
+    pub title: String,
+}
+
+/// Route states, using JMRI's numeric codes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouteState {
+    Unknown,
+    Active,
+    Inactive,
+    Inconsistent,
+}
+
+impl FromStr for RouteState {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "2" => RouteState::Active,
+            "4" => RouteState::Inactive,
+            "8" => RouteState::Inconsistent,
+            _ => RouteState::Unknown,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Route {
+    /// JMRI system name, e.g. `IR3`
+    pub name: String,
+    pub user_name: String,
+    pub state: RouteState,
+}
+
+/// Track power, using JMRI's `PPA` codes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PowerState {
+    Off,
+    On,
+    #[default]
+    Unknown,
+}
+
+impl FromStr for PowerState {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "0" => PowerState::Off,
+            "1" => PowerState::On,
+            _ => PowerState::Unknown,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Velocity {
     value: i16,
 }
@@ -64,6 +366,32 @@ impl Display for VelocityParseError {
 
 impl Error for VelocityParseError {}
 
+#[derive(Debug)]
+pub struct FunctionRangeError {
+    num: FunctionNum,
+}
+
+impl Display for FunctionRangeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "F{} is out of range, functions go up to F{}",
+            self.num, MAX_FUNCTION
+        )
+    }
+}
+
+impl Error for FunctionRangeError {}
+
+/// Checks that `num` is a function JMRI can control.
+pub fn check_function(num: FunctionNum) -> Result<FunctionNum, FunctionRangeError> {
+    if num <= MAX_FUNCTION {
+        Ok(num)
+    } else {
+        Err(FunctionRangeError { num })
+    }
+}
+
 impl FromStr for Velocity {
     type Err = VelocityParseError;
 
@@ -107,6 +435,15 @@ impl Display for Direction {
     }
 }
 
+impl Direction {
+    pub fn reversed(&self) -> Direction {
+        match self {
+            Direction::Reverse => Direction::Forward,
+            Direction::Forward => Direction::Reverse,
+        }
+    }
+}
+
 impl FromStr for Direction {
     type Err = ();
 
@@ -118,26 +455,113 @@ impl FromStr for Direction {
     }
 }
 
+/// Decoder speed step modes, using JMRI's `s` codes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SpeedStepMode {
+    #[default]
+    Steps128,
+    Steps28,
+    Steps27,
+    Steps14,
+}
+
+/// WiThrottle velocities run from 0 to this regardless of the decoder's speed steps.
+pub const MAX_VELOCITY: VelocityValue = 126;
+
+impl SpeedStepMode {
+    /// Number of discrete running steps, excluding stop.
+    pub fn steps(&self) -> u8 {
+        match self {
+            Self::Steps128 => 126,
+            Self::Steps28 => 28,
+            Self::Steps27 => 27,
+            Self::Steps14 => 14,
+        }
+    }
+
+    /// Converts a normalized 0.0–1.0 speed to the velocity of the nearest step.
+    pub fn to_velocity(&self, speed: f32) -> VelocityValue {
+        let steps = self.steps() as f32;
+        let step = (speed.clamp(0.0, 1.0) * steps).round();
+        (step * MAX_VELOCITY as f32 / steps).ceil() as VelocityValue
+    }
+
+    /// Converts a velocity to a normalized 0.0–1.0 speed, snapped to the nearest step.
+    pub fn to_speed(&self, velocity: VelocityValue) -> f32 {
+        let steps = self.steps() as f32;
+        let velocity = velocity.clamp(0, MAX_VELOCITY) as f32;
+        (velocity * steps / MAX_VELOCITY as f32).round() / steps
+    }
+}
+
+impl Display for SpeedStepMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Steps128 => "s1",
+            Self::Steps28 => "s2",
+            Self::Steps27 => "s4",
+            Self::Steps14 => "s8",
+        };
+        f.write_str(s)
+    }
+}
+
+impl FromStr for SpeedStepMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(SpeedStepMode::Steps128),
+            "2" => Ok(SpeedStepMode::Steps28),
+            "4" => Ok(SpeedStepMode::Steps27),
+            "8" => Ok(SpeedStepMode::Steps14),
+            _ => Err(()),
+        }
+    }
+}
+
 #[allow(dead_code)]
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Throttle {
+    throttle: ThrottleId,
     address: String,
     velocity: Velocity,
+    /// The velocity normalized to 0.0–1.0 for the decoder's speed steps
+    #[serde(default)]
+    speed: f32,
     direction: Direction,
+    speed_steps: SpeedStepMode,
     functions: HashSet<u8>,
+    /// Functions held only while pressed; all others latch
+    momentary: HashSet<FunctionNum>,
+    /// Function names from the roster, indexed by function number
+    function_labels: Vec<String>,
 }
 
 #[allow(dead_code)]
 impl Throttle {
-    pub fn new(address: String) -> Self {
+    pub fn new(throttle: ThrottleId, address: String) -> Self {
         Throttle {
+            throttle,
             address,
             velocity: Velocity::new(0),
+            speed: 0.0,
             direction: Direction::Forward,
-            functions: HashSet::with_capacity(29),
+            speed_steps: SpeedStepMode::default(),
+            functions: HashSet::with_capacity(MAX_FUNCTION as usize + 1),
+            momentary: HashSet::new(),
+            function_labels: Vec::new(),
         }
     }
 
+    pub fn get_throttle(&self) -> ThrottleId {
+        self.throttle
+    }
+
+    pub fn get_address(&self) -> &str {
+        self.address.as_str()
+    }
+
     pub fn is_emergency(&self) -> bool {
         self.velocity.value < 0
     }
@@ -148,6 +572,21 @@ impl Throttle {
 
     pub fn set_vel(&mut self, vel: i16) {
         self.velocity.set(vel);
+        self.speed = self.speed_steps.to_speed(self.velocity.value);
+    }
+
+    /// The current speed normalized to 0.0–1.0 for the decoder's speed steps.
+    pub fn get_speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn get_speed_steps(&self) -> SpeedStepMode {
+        self.speed_steps
+    }
+
+    pub fn set_speed_steps(&mut self, mode: SpeedStepMode) {
+        self.speed_steps = mode;
+        self.speed = mode.to_speed(self.velocity.value);
     }
 
     pub fn get_dir(&self) -> &Direction {
@@ -158,10 +597,37 @@ impl Throttle {
         self.direction = dir;
     }
 
+    pub fn get_funcs(&self) -> impl Iterator<Item = &FunctionNum> {
+        self.functions.iter()
+    }
+
     pub fn get_func(&self, num: &u8) -> bool {
         self.functions.contains(num)
     }
 
+    pub fn is_momentary(&self, num: FunctionNum) -> bool {
+        self.momentary.contains(&num)
+    }
+
+    pub fn set_momentary(&mut self, num: FunctionNum, momentary: bool) {
+        if momentary {
+            self.momentary.insert(num);
+        } else {
+            self.momentary.remove(&num);
+        }
+    }
+
+    pub fn get_func_label(&self, num: FunctionNum) -> Option<&str> {
+        self.function_labels
+            .get(num as usize)
+            .map(String::as_str)
+            .filter(|label| !label.is_empty())
+    }
+
+    pub fn set_func_labels(&mut self, labels: Vec<String>) {
+        self.function_labels = labels;
+    }
+
     pub fn set_func(&mut self, num: u8, is_on: bool) {
         if is_on {
             self.functions.insert(num);
@@ -170,3 +636,106 @@ impl Throttle {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_velocity_snaps_to_the_nearest_step() {
+        let cases = [
+            (SpeedStepMode::Steps128, 0.0, 0),
+            (SpeedStepMode::Steps128, 0.5, 63),
+            (SpeedStepMode::Steps128, 1.0, 126),
+            (SpeedStepMode::Steps128, 1.5, 126),
+            (SpeedStepMode::Steps128, -1.0, 0),
+            (SpeedStepMode::Steps28, 1.0 / 28.0, 5),
+            (SpeedStepMode::Steps28, 0.5, 63),
+            (SpeedStepMode::Steps28, 0.01, 0),
+            (SpeedStepMode::Steps27, 1.0 / 27.0, 5),
+            (SpeedStepMode::Steps27, 0.5, 66),
+            (SpeedStepMode::Steps14, 1.0 / 14.0, 9),
+            (SpeedStepMode::Steps14, 0.5, 63),
+            (SpeedStepMode::Steps14, 1.0, 126),
+        ];
+        for (mode, speed, velocity) in cases {
+            assert_eq!(mode.to_velocity(speed), velocity, "{:?} at {}", mode, speed);
+        }
+    }
+
+    #[test]
+    fn to_speed_snaps_to_the_nearest_step() {
+        let cases = [
+            (SpeedStepMode::Steps128, 0, 0.0),
+            (SpeedStepMode::Steps128, 63, 0.5),
+            (SpeedStepMode::Steps128, 126, 1.0),
+            (SpeedStepMode::Steps128, 200, 1.0),
+            (SpeedStepMode::Steps128, -1, 0.0),
+            (SpeedStepMode::Steps28, 5, 1.0 / 28.0),
+            (SpeedStepMode::Steps28, 63, 0.5),
+            (SpeedStepMode::Steps14, 4, 0.0),
+            (SpeedStepMode::Steps14, 5, 1.0 / 14.0),
+            (SpeedStepMode::Steps14, 126, 1.0),
+        ];
+        for (mode, velocity, speed) in cases {
+            assert_eq!(
+                mode.to_speed(velocity),
+                speed,
+                "{:?} at V{}",
+                mode,
+                velocity
+            );
+        }
+    }
+
+    #[test]
+    fn every_step_survives_a_round_trip() {
+        let modes = [
+            SpeedStepMode::Steps128,
+            SpeedStepMode::Steps28,
+            SpeedStepMode::Steps27,
+            SpeedStepMode::Steps14,
+        ];
+        for mode in modes {
+            let steps = mode.steps();
+            for step in 0..=steps {
+                let speed = step as f32 / steps as f32;
+                let velocity = mode.to_velocity(speed);
+                assert!(velocity <= MAX_VELOCITY, "{:?} step {}", mode, step);
+                assert_eq!(mode.to_speed(velocity), speed, "{:?} step {}", mode, step);
+            }
+        }
+    }
+
+    #[test]
+    fn until_next_minute_waits_in_scaled_time() {
+        // Timestamp, scale, next minute and the most it may take to get there
+        let cases = [
+            (1_700_000_000, 1.0, Some(1_700_000_040), 40.0),
+            (1_700_000_000, 60.0, Some(1_700_000_040), 40.0 / 60.0),
+            (1_700_000_040, 4.0, Some(1_700_000_100), 15.0),
+            (1_700_000_000, 0.0, None, 0.0),
+            (1_700_000_000, -1.0, None, 0.0),
+            (1_700_000_000, 1e-30, None, 0.0),
+        ];
+        for (timestamp, scale, minute, most) in cases {
+            let next = DccTime::new(timestamp, scale).until_next_minute();
+            assert_eq!(
+                next.map(|(_, next)| next),
+                minute,
+                "{} at {}",
+                timestamp,
+                scale
+            );
+            if let Some((wait, _)) = next {
+                assert!(wait.as_secs_f64() <= most, "{} at {}", timestamp, scale);
+                assert!(
+                    wait.as_secs_f64() > most - 1.0,
+                    "{} at {}",
+                    timestamp,
+                    scale
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::dcc::FunctionRangeError;
+use crate::server::SessionId;
+
+/// Why a client's request was refused, sent back to it in `ServerEvent::Error`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RequestError {
+    /// The request isn't valid JSON or doesn't match the protocol.
+    Parse { message: String },
+    /// The address isn't a short or long DCC address, e.g. `S3` or `L4012`.
+    InvalidAddress { address: String },
+    /// No session has acquired the address.
+    UnknownAddress { address: String },
+    /// Another session has acquired the address.
+    NotOwned { address: String, owner: SessionId },
+    /// The bridge isn't connected to JMRI, so the command can't be sent.
+    JmriOffline,
+    /// A value in the request is out of range or not accepted here.
+    InvalidValue { message: String },
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Parse { message } => write!(f, "unreadable request: {}", message),
+            RequestError::InvalidAddress { address } => write!(f, "invalid address '{}'", address),
+            RequestError::UnknownAddress { address } => {
+                write!(f, "{} has not been acquired", address)
+            }
+            RequestError::NotOwned { address, owner } => {
+                write!(f, "{} is owned by session {}", address, owner)
+            }
+            RequestError::JmriOffline => f.write_str("JMRI is offline"),
+            RequestError::InvalidValue { message } => f.write_str(message),
+        }
+    }
+}
+
+impl Error for RequestError {}
+
+impl From<FunctionRangeError> for RequestError {
+    fn from(e: FunctionRangeError) -> Self {
+        RequestError::InvalidValue {
+            message: e.to_string(),
+        }
+    }
+}
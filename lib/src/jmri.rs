@@ -1,21 +1,102 @@
 use std::fmt::{Display, Formatter};
-use std::io;
-use std::io::ErrorKind;
 use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
 use tokio::net::TcpStream;
-use tokio::sync::broadcast;
-use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, watch};
 use tokio::task::JoinHandle;
 
-// TODO: Check whether this changes based on JMRI host platform
+use crate::dcc::ThrottleId;
+use crate::parse;
+use crate::parse::REGEXES;
+
+/// Line terminator used when writing; `\n`, `\r` and `\r\n` are all accepted when reading.
 pub const RETURN: &str = "\n";
 
+/// Why a connection to JMRI ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Termination {
+    /// JMRI closed the connection.
+    Eof,
+    /// Reading from or writing to JMRI failed.
+    Error(String),
+    /// The server dropped its end of the stream, so there is nothing left to relay.
+    Closed,
+}
+
+impl Display for Termination {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Termination::Eof => f.write_str("closed by JMRI"),
+            Termination::Error(e) => write!(f, "error: {}", e),
+            Termination::Closed => f.write_str("channel closed"),
+        }
+    }
+}
+
+/// Splits JMRI's byte stream into lines, holding on to partial lines until they are completed.
+#[derive(Default)]
+pub struct LineCodec {
+    buffer: Vec<u8>,
+}
+
+impl LineCodec {
+    /// Appends `bytes`, returning every non-empty line completed by them.
+    pub fn decode(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+
+        let end = match self.buffer.iter().rposition(|b| *b == b'\n' || *b == b'\r') {
+            Some(end) => end,
+            None => return Vec::new(),
+        };
+        let rest = self.buffer.split_off(end + 1);
+        let complete = std::mem::replace(&mut self.buffer, rest);
+
+        // A `\r\n` pair yields an empty line between them, which is dropped with the rest
+        complete
+            .split(|b| *b == b'\n' || *b == b'\r')
+            .filter_map(Self::line)
+            .collect()
+    }
+
+    /// Returns any unterminated trailing line, e.g. once JMRI has closed the connection.
+    pub fn finish(&mut self) -> Option<String> {
+        let rest = std::mem::take(&mut self.buffer);
+        Self::line(&rest)
+    }
+
+    fn line(bytes: &[u8]) -> Option<String> {
+        let line = String::from_utf8_lossy(bytes);
+        let line = line.trim();
+        if line.is_empty() {
+            None
+        } else {
+            Some(line.to_string())
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum JmriMessage {
+    /// One or more lines for JMRI, joined by `RETURN`.
     Send(String),
     Receive(String),
+    /// The connection to JMRI was (re-)established and the handshake sent.
+    Connected,
+    /// The connection to JMRI ended; a reconnection is being attempted.
+    Disconnected(Termination),
+}
+
+impl JmriMessage {
+    /// A multi-throttle command, e.g. `MTAS3<;>V20` for `throttle` `T`, `action` `A`.
+    pub fn throttle(throttle: ThrottleId, action: char, address: &str, command: &str) -> String {
+        format!("M{}{}{}<;>{}", throttle, action, address, command)
+    }
 }
 
 impl Display for JmriMessage {
@@ -23,81 +104,376 @@ impl Display for JmriMessage {
         match self {
             JmriMessage::Send(msg) => f.write_str(["Send: ", msg].concat().as_str()),
             JmriMessage::Receive(msg) => f.write_str(["Receive: ", msg].concat().as_str()),
+            JmriMessage::Connected => f.write_str("Connected"),
+            JmriMessage::Disconnected(reason) => write!(f, "Disconnected: {}", reason),
+        }
+    }
+}
+
+/// The WiThrottle protocol major version this crate speaks.
+pub const SUPPORTED_VERSION: u32 = 2;
+
+/// What JMRI announces about itself on connecting.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct JmriServerInfo {
+    /// WiThrottle protocol version, from `VN`
+    pub version: Option<String>,
+    /// Server type, from `HT`, e.g. `JMRI`
+    pub server_type: Option<String>,
+    /// Server description, from `Ht`
+    pub description: Option<String>,
+    /// Port of JMRI's web server, from `PW`
+    pub web_port: Option<u16>,
+}
+
+impl JmriServerInfo {
+    /// Records `msg` if it is one of JMRI's announcements, returning whether it was.
+    pub fn update(&mut self, msg: &str) -> bool {
+        let captures = match REGEXES.server_info.captures(msg) {
+            Some(captures) => captures,
+            None => return false,
+        };
+
+        let value = captures.name("value").unwrap().as_str().to_string();
+        match captures.name("kind").unwrap().as_str() {
+            "VN" => self.version = Some(value),
+            "HT" => self.server_type = Some(value),
+            "Ht" => self.description = Some(value),
+            _ => self.web_port = u16::from_str(&value).ok(),
+        }
+        true
+    }
+
+    pub fn major_version(&self) -> Option<u32> {
+        let version = self.version.as_ref()?;
+        u32::from_str(version.split('.').next()?).ok()
+    }
+
+    /// Whether JMRI speaks a protocol version this crate understands, assuming so until told.
+    pub fn is_supported(&self) -> bool {
+        match self.major_version() {
+            Some(version) => version == SUPPORTED_VERSION,
+            None => true,
         }
     }
 }
 
-#[allow(dead_code)]
+/// Pings are sent at this fraction of JMRI's heartbeat timeout to leave room for latency.
+const HEARTBEAT_FRACTION: u32 = 2;
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// How long a connection has to last before reconnecting starts again from the shortest delay.
+const STABLE_CONNECTION: Duration = Duration::from_secs(10);
+
+/// Lines received from JMRI are held here until the server takes them. Once it is full, reading
+/// from JMRI waits rather than dropping anything.
+const RECEIVE_CAPACITY: usize = 256;
+
+type HeartbeatSender = Arc<watch::Sender<Option<Duration>>>;
+type ServerInfoSender = Arc<watch::Sender<JmriServerInfo>>;
+
+/// Queues requests for JMRI. Requests queued while disconnected are discarded on reconnecting.
+pub type JmriSender = mpsc::UnboundedSender<JmriMessage>;
+
 pub struct JmriStream {
-    listen_handle: JoinHandle<io::Result<()>>,
-    send_handle: JoinHandle<io::Result<()>>,
-    channel: broadcast::Sender<JmriMessage>,
+    connection_handle: JoinHandle<Termination>,
+    heartbeat_handle: JoinHandle<()>,
+    sender: JmriSender,
+    receiver: Option<mpsc::Receiver<JmriMessage>>,
+    heartbeat: watch::Receiver<Option<Duration>>,
+    connected: watch::Receiver<bool>,
+    server_info: watch::Receiver<JmriServerInfo>,
 }
 
 impl JmriStream {
-    pub async fn new(address: SocketAddr) -> io::Result<JmriStream> {
-        let stream = TcpStream::connect(address).await?;
-        let (stream_reader, mut stream_writer) = stream.into_split();
-        let mut stream_reader = BufReader::new(stream_reader);
-
-        let (channel, _) = broadcast::channel::<JmriMessage>(32);
-
-        let listen_handle_tx = channel.clone();
-        let listen_handle: JoinHandle<io::Result<()>> = tokio::spawn(async move {
-            let mut line = String::new();
-            loop {
-                stream_reader.read_line(&mut line).await?;
-
-                let lines = line
-                    .split(RETURN)
-                    .map(|line| line.trim())
-                    .filter(|line| !line.is_empty());
-                for line in lines {
-                    let message = JmriMessage::Receive(line.to_string());
-                    if let Err(e) = listen_handle_tx.send(message) {
-                        return Err(io::Error::new(ErrorKind::Interrupted, e));
+    /// Connects to JMRI in the background, sending `handshake` on every (re-)connection
+    /// and reconnecting with exponential backoff whenever the connection drops.
+    pub fn new(address: SocketAddr, handshake: Vec<String>) -> JmriStream {
+        let (sender, send_rx) = mpsc::unbounded_channel::<JmriMessage>();
+        let (receive_tx, receiver) = mpsc::channel::<JmriMessage>(RECEIVE_CAPACITY);
+        let (heartbeat_tx, heartbeat) = watch::channel::<Option<Duration>>(None);
+        let (connected_tx, connected) = watch::channel(false);
+        let (server_info_tx, server_info) = watch::channel(JmriServerInfo::default());
+
+        let connection_handle = make_connection_handle(
+            address,
+            handshake,
+            sender.clone(),
+            send_rx,
+            receive_tx,
+            Arc::new(heartbeat_tx),
+            connected_tx,
+            Arc::new(server_info_tx),
+        );
+        let heartbeat_handle = make_heartbeat_handle(sender.clone(), heartbeat.clone());
+
+        JmriStream {
+            connection_handle,
+            heartbeat_handle,
+            sender,
+            receiver: Some(receiver),
+            heartbeat,
+            connected,
+            server_info,
+        }
+    }
+
+    /// Whether JMRI is currently connected.
+    pub fn is_connected(&self) -> bool {
+        *self.connected.borrow()
+    }
+
+    /// The heartbeat timeout JMRI announced, if it requires one.
+    pub fn heartbeat(&self) -> Option<Duration> {
+        *self.heartbeat.borrow()
+    }
+
+    /// What JMRI announced about itself on the current connection, updated as it arrives.
+    pub fn server_info(&self) -> watch::Receiver<JmriServerInfo> {
+        self.server_info.clone()
+    }
+
+    /// Waits for the stream's background tasks to stop, which only happens once there is
+    /// nothing left to relay to or one of them panicked.
+    pub async fn finished(&mut self) -> Termination {
+        tokio::select! {
+            result = &mut self.connection_handle => {
+                result.unwrap_or_else(|e| Termination::Error(e.to_string()))
+            }
+            result = &mut self.heartbeat_handle => match result {
+                Ok(()) => Termination::Closed,
+                Err(e) => Termination::Error(e.to_string()),
+            },
+        }
+    }
+
+    pub fn clone_sender(&mut self) -> JmriSender {
+        self.sender.clone()
+    }
+
+    /// Everything received from JMRI, along with each connection and disconnection, in order
+    /// and without gaps. There is only one receiver, so this returns `None` once taken.
+    pub fn take_receiver(&mut self) -> Option<mpsc::Receiver<JmriMessage>> {
+        self.receiver.take()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn make_connection_handle(
+    address: SocketAddr,
+    handshake: Vec<String>,
+    sender: JmriSender,
+    mut send_rx: mpsc::UnboundedReceiver<JmriMessage>,
+    receive_tx: mpsc::Sender<JmriMessage>,
+    heartbeat: HeartbeatSender,
+    connected: watch::Sender<bool>,
+    server_info: ServerInfoSender,
+) -> JoinHandle<Termination> {
+    tokio::spawn(async move {
+        let mut delay = MIN_RECONNECT_DELAY;
+        loop {
+            if let Ok(stream) = TcpStream::connect(address).await {
+                let connected_at = Instant::now();
+                let reason = run_connection(
+                    stream,
+                    &handshake,
+                    &sender,
+                    &mut send_rx,
+                    &receive_tx,
+                    &heartbeat,
+                    &connected,
+                    &server_info,
+                )
+                .await;
+
+                let _ = connected.send(false);
+                let _ = heartbeat.send(None);
+                let _ = server_info.send(JmriServerInfo::default());
+                if reason == Termination::Closed {
+                    return reason;
+                }
+                if receive_tx
+                    .send(JmriMessage::Disconnected(reason))
+                    .await
+                    .is_err()
+                {
+                    return Termination::Closed;
+                }
+
+                // Only a connection that stayed up shows JMRI is healthy, otherwise keep backing off
+                if connected_at.elapsed() >= STABLE_CONNECTION {
+                    delay = MIN_RECONNECT_DELAY;
+                }
+            }
+
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    })
+}
+
+/// Drives a single JMRI connection until either direction fails or JMRI closes it.
+#[allow(clippy::too_many_arguments)]
+async fn run_connection(
+    stream: TcpStream,
+    handshake: &[String],
+    sender: &JmriSender,
+    send_rx: &mut mpsc::UnboundedReceiver<JmriMessage>,
+    receive_tx: &mpsc::Sender<JmriMessage>,
+    heartbeat: &HeartbeatSender,
+    connected: &watch::Sender<bool>,
+    server_info: &ServerInfoSender,
+) -> Termination {
+    let (stream_reader, mut stream_writer) = stream.into_split();
+
+    // Anything queued while offline was meant for the previous connection; its throttles are
+    // restored by the server once it sees `Connected`
+    while send_rx.try_recv().is_ok() {}
+
+    for message in handshake {
+        let line = [message.as_str(), RETURN].concat();
+        if let Err(e) = stream_writer.write_all(line.as_bytes()).await {
+            return Termination::Error(e.to_string());
+        }
+    }
+
+    let mut listen_handle = make_listen_handle(
+        stream_reader,
+        sender.clone(),
+        receive_tx.clone(),
+        heartbeat.clone(),
+        server_info.clone(),
+    );
+    let _ = connected.send(true);
+    if receive_tx.send(JmriMessage::Connected).await.is_err() {
+        listen_handle.abort();
+        return Termination::Closed;
+    }
+
+    loop {
+        let msg = tokio::select! {
+            result = &mut listen_handle => {
+                return result.unwrap_or_else(|e| Termination::Error(e.to_string()));
+            }
+            msg = send_rx.recv() => match msg {
+                Some(JmriMessage::Send(msg)) => msg,
+                Some(_) => continue,
+                None => {
+                    listen_handle.abort();
+                    return Termination::Closed;
+                }
+            },
+        };
+
+        let line = [msg.as_str(), RETURN].concat();
+        if let Err(e) = stream_writer.write_all(line.as_bytes()).await {
+            listen_handle.abort();
+            return Termination::Error(e.to_string());
+        }
+    }
+}
+
+fn make_listen_handle(
+    mut stream_reader: OwnedReadHalf,
+    sender: JmriSender,
+    receive_tx: mpsc::Sender<JmriMessage>,
+    heartbeat_tx: HeartbeatSender,
+    server_info_tx: ServerInfoSender,
+) -> JoinHandle<Termination> {
+    tokio::spawn(async move {
+        let mut codec = LineCodec::default();
+        let mut buffer = [0u8; 1024];
+        loop {
+            let lines = match stream_reader.read(&mut buffer).await {
+                Ok(0) => {
+                    if let Some(line) = codec.finish() {
+                        let _ = receive_tx.send(JmriMessage::Receive(line)).await;
+                    }
+                    return Termination::Eof;
+                }
+                Ok(read) => codec.decode(&buffer[..read]),
+                Err(e) => return Termination::Error(e.to_string()),
+            };
+
+            for line in lines {
+                if let Some(timeout) = parse::heartbeat(&line) {
+                    // JMRI only enforces the heartbeat once we opt in with `*+`
+                    if timeout > 0 {
+                        let _ = sender.send(JmriMessage::Send("*+".to_string()));
+                        let _ = heartbeat_tx.send(Some(Duration::from_secs(timeout)));
+                    } else {
+                        let _ = heartbeat_tx.send(None);
                     }
                 }
-                line.clear();
+
+                server_info_tx.send_if_modified(|info| info.update(&line));
+
+                if receive_tx.send(JmriMessage::Receive(line)).await.is_err() {
+                    return Termination::Closed;
+                }
             }
-        });
-
-        let mut send_rx = channel.subscribe();
-        let send_handle: JoinHandle<io::Result<()>> = tokio::spawn(async move {
-            loop {
-                let msg = match send_rx.recv().await {
-                    Ok(msg) => match msg {
-                        JmriMessage::Send(msg) => msg,
-                        JmriMessage::Receive(_) => continue,
-                    },
-                    Err(e) => match e {
-                        RecvError::Closed => {
-                            break;
-                        }
-                        RecvError::Lagged(_) => continue,
-                    },
-                };
+        }
+    })
+}
 
-                stream_writer
-                    .write_all([msg.as_str(), RETURN].concat().as_bytes())
-                    .await?;
+fn make_heartbeat_handle(
+    sender: JmriSender,
+    mut timeout: watch::Receiver<Option<Duration>>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let current = *timeout.borrow();
+            match current {
+                None => {
+                    if timeout.changed().await.is_err() {
+                        break;
+                    }
+                }
+                Some(current) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(current / HEARTBEAT_FRACTION) => {
+                            if sender.send(JmriMessage::Send("*".to_string())).is_err() {
+                                break;
+                            }
+                        }
+                        changed = timeout.changed() => {
+                            if changed.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
             }
+        }
+    })
+}
 
-            Ok(())
-        });
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(JmriStream {
-            listen_handle,
-            send_handle,
-            channel,
-        })
+    #[test]
+    fn crlf_split_across_reads() {
+        let mut codec = LineCodec::default();
+        assert_eq!(codec.decode(b"PPA1\r"), vec!["PPA1"]);
+        assert_eq!(codec.decode(b"\nPFT65<;>1.0\r\n"), vec!["PFT65<;>1.0"]);
+        assert_eq!(codec.finish(), None);
     }
 
-    pub fn clone_sender(&mut self) -> broadcast::Sender<JmriMessage> {
-        self.channel.clone()
+    #[test]
+    fn partial_line_held_until_completed() {
+        let mut codec = LineCodec::default();
+        assert!(codec.decode(b"MTAS3<;>").is_empty());
+        assert_eq!(codec.decode(b"V10\nMTAS3"), vec!["MTAS3<;>V10"]);
+        assert_eq!(codec.decode(b"<;>R1\n"), vec!["MTAS3<;>R1"]);
     }
 
-    pub fn subscribe(&mut self) -> broadcast::Receiver<JmriMessage> {
-        self.channel.subscribe()
+    #[test]
+    fn finish_returns_unterminated_line_at_eof() {
+        let mut codec = LineCodec::default();
+        assert_eq!(codec.decode(b"PPA0\nHMgoodbye"), vec!["PPA0"]);
+        assert_eq!(codec.finish(), Some("HMgoodbye".to_string()));
+        assert_eq!(codec.finish(), None);
     }
 }
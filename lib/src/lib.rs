@@ -1,4 +1,5 @@
 pub mod dcc;
+pub mod error;
 pub mod jmri;
 pub mod parse;
 pub mod server;
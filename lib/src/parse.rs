@@ -1,59 +1,837 @@
-use crate::dcc::{Direction, FunctionNum, TimeScale, Timestamp, VelocityValue};
-use once_cell::sync::{Lazy};
+use crate::dcc;
+use crate::dcc::{
+    AddressLength, ClockEvent, Consist, Direction, FunctionNum, PowerState, Roster, RosterEntry,
+    Route, RouteState, SpeedStepMode, ThrottleId, TimeScale, Timestamp, Turnout, TurnoutState,
+    TurnoutStateTitle, TurnoutTitles, VelocityValue,
+};
+use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum JmriUpdate {
+    Acquire {
+        #[serde(default)]
+        throttle: ThrottleId,
+        address: String,
+    },
+    Release {
+        #[serde(default)]
+        throttle: ThrottleId,
+        address: String,
+    },
+    /// A function's state, or a request to force it on or off when sent by a client.
     Function {
+        #[serde(default)]
+        throttle: ThrottleId,
+        address: String,
         num: FunctionNum,
         is_on: bool,
     },
-    Velocity(VelocityValue),
-    Direction(Direction),
+    /// A client pressing or releasing a function button.
+    FunctionButton {
+        #[serde(default)]
+        throttle: ThrottleId,
+        address: String,
+        num: FunctionNum,
+        pressed: bool,
+    },
+    /// Whether a function is momentary (held while pressed) or latching.
+    FunctionMode {
+        #[serde(default)]
+        throttle: ThrottleId,
+        address: String,
+        num: FunctionNum,
+        momentary: bool,
+    },
+    /// A velocity, or -1 to emergency stop. `speed` is the velocity normalized to 0.0–1.0,
+    /// filled in by the bridge for clients.
+    Velocity {
+        #[serde(default)]
+        throttle: ThrottleId,
+        address: String,
+        value: VelocityValue,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        speed: Option<f32>,
+    },
+    Direction {
+        #[serde(default)]
+        throttle: ThrottleId,
+        address: String,
+        direction: Direction,
+    },
+    SpeedSteps {
+        #[serde(default)]
+        throttle: ThrottleId,
+        address: String,
+        mode: SpeedStepMode,
+    },
+    /// Function names, indexed by function number; unnamed functions are empty.
+    FunctionLabels {
+        #[serde(default)]
+        throttle: ThrottleId,
+        address: String,
+        labels: Vec<String>,
+    },
+    /// JMRI asking whether to take an address another throttle holds, or the
+    /// confirmation to do so when sent by a client.
+    Steal {
+        #[serde(default)]
+        throttle: ThrottleId,
+        address: String,
+    },
+    EmergencyStop {
+        #[serde(default)]
+        throttle: ThrottleId,
+        address: String,
+    },
+    Idle {
+        #[serde(default)]
+        throttle: ThrottleId,
+        address: String,
+    },
+    /// A client request for a normalized 0.0–1.0 speed, sent to JMRI as a `Velocity`.
+    Speed {
+        #[serde(default)]
+        throttle: ThrottleId,
+        address: String,
+        speed: f32,
+    },
     Time {
         timestamp: Timestamp,
         scale: TimeScale,
     },
+    Clock(ClockEvent),
+    Roster(Roster),
+    /// The number of consists JMRI is about to send, which replace any it sent before.
+    ConsistCount(usize),
+    /// A consist defined in JMRI, or a session's ad-hoc consist after it changes.
+    Consist(Consist),
+    Consists(Vec<Consist>),
+    /// Adds an owned locomotive to the session's ad-hoc consist.
+    ConsistAdd {
+        #[serde(default)]
+        throttle: ThrottleId,
+        address: String,
+        #[serde(default)]
+        reversed: bool,
+    },
+    /// Removes a locomotive from the session's ad-hoc consist.
+    ConsistRemove {
+        #[serde(default)]
+        throttle: ThrottleId,
+        address: String,
+    },
+    Turnouts(Vec<Turnout>),
+    TurnoutTitles(TurnoutTitles),
+    /// A turnout's state, or a request to set it when sent by a client.
+    Turnout {
+        name: String,
+        state: TurnoutState,
+    },
+    ToggleTurnout {
+        name: String,
+    },
+    Routes(Vec<Route>),
+    /// A route's state, or a request to set it active when sent by a client.
+    Route {
+        name: String,
+        state: RouteState,
+    },
+    Power(PowerState),
+    /// JMRI's heartbeat timeout in seconds, zero when disabled.
+    Heartbeat(u64),
+    /// Whether the bridge is currently connected to JMRI.
+    Online(bool),
+    /// A message JMRI wants shown to the operator, e.g. why an address was refused.
+    Alert {
+        level: AlertLevel,
+        text: String,
+    },
+}
+
+/// How urgently JMRI wants a message shown, from `HM` and `Hm` respectively.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlertLevel {
+    Alert,
+    Info,
+}
+
+impl JmriUpdate {
+    /// The multi-throttle this update concerns, if any.
+    pub fn throttle(&self) -> Option<ThrottleId> {
+        match self {
+            JmriUpdate::Acquire { throttle, .. }
+            | JmriUpdate::Release { throttle, .. }
+            | JmriUpdate::Function { throttle, .. }
+            | JmriUpdate::Velocity { throttle, .. }
+            | JmriUpdate::Direction { throttle, .. }
+            | JmriUpdate::SpeedSteps { throttle, .. }
+            | JmriUpdate::Speed { throttle, .. }
+            | JmriUpdate::EmergencyStop { throttle, .. }
+            | JmriUpdate::Idle { throttle, .. }
+            | JmriUpdate::Steal { throttle, .. }
+            | JmriUpdate::FunctionLabels { throttle, .. }
+            | JmriUpdate::FunctionButton { throttle, .. }
+            | JmriUpdate::FunctionMode { throttle, .. }
+            | JmriUpdate::ConsistAdd { throttle, .. }
+            | JmriUpdate::ConsistRemove { throttle, .. } => Some(*throttle),
+            _ => None,
+        }
+    }
+
+    /// Points a locomotive update at `id`, leaving other updates untouched.
+    pub fn set_throttle(&mut self, id: ThrottleId) {
+        match self {
+            JmriUpdate::Acquire { throttle, .. }
+            | JmriUpdate::Release { throttle, .. }
+            | JmriUpdate::Function { throttle, .. }
+            | JmriUpdate::Velocity { throttle, .. }
+            | JmriUpdate::Direction { throttle, .. }
+            | JmriUpdate::SpeedSteps { throttle, .. }
+            | JmriUpdate::Speed { throttle, .. }
+            | JmriUpdate::EmergencyStop { throttle, .. }
+            | JmriUpdate::Idle { throttle, .. }
+            | JmriUpdate::Steal { throttle, .. }
+            | JmriUpdate::FunctionLabels { throttle, .. }
+            | JmriUpdate::FunctionButton { throttle, .. }
+            | JmriUpdate::FunctionMode { throttle, .. }
+            | JmriUpdate::ConsistAdd { throttle, .. }
+            | JmriUpdate::ConsistRemove { throttle, .. } => *throttle = id,
+            _ => {}
+        }
+    }
+
+    /// Points a locomotive update at another address, leaving other updates untouched.
+    pub fn set_address(&mut self, new: String) {
+        match self {
+            JmriUpdate::Acquire { address, .. }
+            | JmriUpdate::Release { address, .. }
+            | JmriUpdate::Function { address, .. }
+            | JmriUpdate::Velocity { address, .. }
+            | JmriUpdate::Direction { address, .. }
+            | JmriUpdate::SpeedSteps { address, .. }
+            | JmriUpdate::Speed { address, .. }
+            | JmriUpdate::EmergencyStop { address, .. }
+            | JmriUpdate::Idle { address, .. }
+            | JmriUpdate::Steal { address, .. }
+            | JmriUpdate::FunctionLabels { address, .. }
+            | JmriUpdate::FunctionButton { address, .. }
+            | JmriUpdate::FunctionMode { address, .. }
+            | JmriUpdate::ConsistAdd { address, .. }
+            | JmriUpdate::ConsistRemove { address, .. } => *address = new,
+            _ => {}
+        }
+    }
+
+    /// The function number this update concerns, if any.
+    pub fn function(&self) -> Option<FunctionNum> {
+        match self {
+            JmriUpdate::Function { num, .. }
+            | JmriUpdate::FunctionButton { num, .. }
+            | JmriUpdate::FunctionMode { num, .. } => Some(*num),
+            _ => None,
+        }
+    }
+
+    /// The locomotive address this update concerns, if any.
+    pub fn address(&self) -> Option<&str> {
+        match self {
+            JmriUpdate::Acquire { address, .. }
+            | JmriUpdate::Release { address, .. }
+            | JmriUpdate::Function { address, .. }
+            | JmriUpdate::Velocity { address, .. }
+            | JmriUpdate::Direction { address, .. }
+            | JmriUpdate::SpeedSteps { address, .. }
+            | JmriUpdate::Speed { address, .. }
+            | JmriUpdate::EmergencyStop { address, .. }
+            | JmriUpdate::Idle { address, .. }
+            | JmriUpdate::Steal { address, .. }
+            | JmriUpdate::FunctionLabels { address, .. }
+            | JmriUpdate::FunctionButton { address, .. }
+            | JmriUpdate::FunctionMode { address, .. }
+            | JmriUpdate::ConsistAdd { address, .. }
+            | JmriUpdate::ConsistRemove { address, .. } => Some(address.as_str()),
+            _ => None,
+        }
+    }
 }
 
 pub struct Regexes {
+    pub address: Regex,
+    pub throttle: Regex,
     pub function: Regex,
+    pub function_mode: Regex,
     pub velocity: Regex,
     pub direction: Regex,
+    pub speed_steps: Regex,
     pub clock: Regex,
+    pub roster: Regex,
+    pub consist_count: Regex,
+    pub consist: Regex,
+    pub turnouts: Regex,
+    pub turnout_titles: Regex,
+    pub turnout: Regex,
+    pub routes: Regex,
+    pub route: Regex,
+    pub power: Regex,
+    pub heartbeat: Regex,
+    pub alert: Regex,
+    pub server_info: Regex,
 }
 
 impl Default for Regexes {
     fn default() -> Self {
         Regexes {
-            function: Regex::new(r"F(?P<on>[01])(?P<num>\d\d?)").unwrap(),
-            velocity: Regex::new(r"V(?P<v>-?\d{1,3})").unwrap(),
-            direction: Regex::new(r"(?P<d>R[01])").unwrap(),
+            address: Regex::new(r"^(?P<length>[SL])(?P<number>\d{1,5})$").unwrap(),
+            throttle: Regex::new(
+                r"^M(?P<throttle>[0-9A-Za-z])(?P<action>[+\-ALS])(?P<address>[SL]\d{1,5})<;>(?P<rest>.*)$",
+            )
+            .unwrap(),
+            function: Regex::new(r"^F(?P<on>[01])(?P<num>\d{1,2})$").unwrap(),
+            function_mode: Regex::new(r"^m(?P<momentary>[01])(?P<num>\d{1,2})$").unwrap(),
+            velocity: Regex::new(r"^V(?P<v>-?\d{1,3})$").unwrap(),
+            direction: Regex::new(r"^(?P<d>R[01])$").unwrap(),
+            speed_steps: Regex::new(r"^s(?P<mode>\d+)$").unwrap(),
             clock: Regex::new(r"PFT(?P<time>\d+)<;>(?P<scale>\d+(?:\.\d+)?)").unwrap(),
+            roster: Regex::new(r"^RL(?P<count>\d+)(?P<entries>.*)$").unwrap(),
+            consist_count: Regex::new(r"^RC[CL](?P<count>\d+)$").unwrap(),
+            consist: Regex::new(r"^RCD\}\|\{(?P<entries>.*)$").unwrap(),
+            turnouts: Regex::new(r"^PTL(?P<entries>.*)$").unwrap(),
+            turnout_titles: Regex::new(r"^PTT(?P<entries>.*)$").unwrap(),
+            turnout: Regex::new(r"^PTA(?P<state>\d)(?P<name>.+)$").unwrap(),
+            routes: Regex::new(r"^PRL(?P<entries>.*)$").unwrap(),
+            route: Regex::new(r"^PRA(?P<state>\d)(?P<name>.+)$").unwrap(),
+            power: Regex::new(r"^PPA(?P<state>[012])$").unwrap(),
+            heartbeat: Regex::new(r"^\*(?P<seconds>\d+)$").unwrap(),
+            server_info: Regex::new(r"^(?P<kind>VN|HT|Ht|PW)(?P<value>.*)$").unwrap(),
+            alert: Regex::new(r"^H(?P<level>[Mm])(?P<text>.*)$").unwrap(),
         }
     }
 }
 
 pub static REGEXES: Lazy<Regexes> = Lazy::new(Regexes::default);
 
+/// The highest short DCC address
+const MAX_SHORT_ADDRESS: u16 = 127;
+/// The highest long DCC address
+const MAX_LONG_ADDRESS: u16 = 10239;
+
+/// Whether `address` is a valid short (`S`) or long (`L`) DCC address, e.g. `S3` or `L4012`.
+pub fn is_address(address: &str) -> bool {
+    let captures = match REGEXES.address.captures(address) {
+        Some(captures) => captures,
+        None => return false,
+    };
+    let max = match captures.name("length").unwrap().as_str() {
+        "S" => MAX_SHORT_ADDRESS,
+        _ => MAX_LONG_ADDRESS,
+    };
+    u16::from_str(captures.name("number").unwrap().as_str()).is_ok_and(|number| number <= max)
+}
+
+/// Parses JMRI's `*<seconds>` heartbeat announcement.
+pub fn heartbeat(msg: &str) -> Option<u64> {
+    let captures = REGEXES.heartbeat.captures(msg)?;
+    u64::from_str(captures.name("seconds").unwrap().as_str()).ok()
+}
+
 pub fn jmri_message(msg: &str) -> Option<JmriUpdate> {
+    if let Some(captures) = REGEXES.throttle.captures(msg) {
+        let throttle = captures.name("throttle").unwrap().as_str();
+        let throttle = ThrottleId::new(throttle.chars().next().unwrap()).unwrap();
+        let address = captures.name("address").unwrap().as_str().to_string();
+        let rest = captures.name("rest").unwrap().as_str();
+        return match captures.name("action").unwrap().as_str() {
+            "+" => Some(JmriUpdate::Acquire { throttle, address }),
+            "-" => Some(JmriUpdate::Release { throttle, address }),
+            "S" => Some(JmriUpdate::Steal { throttle, address }),
+            "L" => Some(JmriUpdate::FunctionLabels {
+                throttle,
+                address,
+                labels: function_labels(rest),
+            }),
+            _ => throttle_action(throttle, address, rest),
+        };
+    }
+
+    if let Some(captures) = REGEXES.roster.captures(msg) {
+        let entries = captures.name("entries").unwrap().as_str();
+        return Some(JmriUpdate::Roster(roster(entries)));
+    }
+
+    if let Some(captures) = REGEXES.consist_count.captures(msg) {
+        let count = usize::from_str(captures.name("count").unwrap().as_str()).ok()?;
+        return Some(JmriUpdate::ConsistCount(count));
+    }
+
+    if let Some(captures) = REGEXES.consist.captures(msg) {
+        let entries = captures.name("entries").unwrap().as_str();
+        return consist(entries).map(JmriUpdate::Consist);
+    }
+
+    if let Some(captures) = REGEXES.turnouts.captures(msg) {
+        let entries = captures.name("entries").unwrap().as_str();
+        return Some(JmriUpdate::Turnouts(turnouts(entries)));
+    }
+
+    if let Some(captures) = REGEXES.turnout_titles.captures(msg) {
+        let entries = captures.name("entries").unwrap().as_str();
+        return turnout_titles(entries).map(JmriUpdate::TurnoutTitles);
+    }
+
+    if let Some(captures) = REGEXES.turnout.captures(msg) {
+        let name = captures.name("name").unwrap().as_str().to_string();
+        let state = TurnoutState::from_str(captures.name("state").unwrap().as_str()).unwrap();
+        return Some(JmriUpdate::Turnout { name, state });
+    }
+
+    if let Some(captures) = REGEXES.routes.captures(msg) {
+        let entries = captures.name("entries").unwrap().as_str();
+        return Some(JmriUpdate::Routes(routes(entries)));
+    }
+
+    if let Some(captures) = REGEXES.route.captures(msg) {
+        let name = captures.name("name").unwrap().as_str().to_string();
+        let state = RouteState::from_str(captures.name("state").unwrap().as_str()).unwrap();
+        return Some(JmriUpdate::Route { name, state });
+    }
+
+    if let Some(captures) = REGEXES.power.captures(msg) {
+        let state = PowerState::from_str(captures.name("state").unwrap().as_str()).unwrap();
+        return Some(JmriUpdate::Power(state));
+    }
+
+    if let Some(captures) = REGEXES.clock.captures(msg) {
+        let timestamp = Timestamp::from_str(captures.name("time").unwrap().as_str()).ok()?;
+        let scale = TimeScale::from_str(captures.name("scale").unwrap().as_str()).ok()?;
+        return Some(JmriUpdate::Time { timestamp, scale });
+    }
+
+    if let Some(captures) = REGEXES.alert.captures(msg) {
+        let level = match captures.name("level").unwrap().as_str() {
+            "M" => AlertLevel::Alert,
+            _ => AlertLevel::Info,
+        };
+        let text = captures.name("text").unwrap().as_str().to_string();
+        return Some(JmriUpdate::Alert { level, text });
+    }
+
+    if let Some(seconds) = heartbeat(msg) {
+        return Some(JmriUpdate::Heartbeat(seconds));
+    }
+
+    None
+}
+
+/// Parses the `]\[`-separated labels of an `L` throttle message. Labels are positional,
+/// so empty ones are kept.
+fn function_labels(labels: &str) -> Vec<String> {
+    match labels.strip_prefix("]\\[") {
+        Some(labels) => labels.split("]\\[").map(str::to_string).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Parses the `]\[name}|{address}|{S/L`-separated entries of an `RL` message,
+/// skipping any that are malformed.
+fn roster(entries: &str) -> Roster {
+    let entries = entries
+        .split("]\\[")
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let mut fields = entry.split("}|{");
+            let name = fields.next()?.to_string();
+            let address = u16::from_str(fields.next()?).ok()?;
+            let length = AddressLength::from_str(fields.next()?).ok()?;
+            Some(RosterEntry {
+                name,
+                address,
+                length,
+            })
+        })
+        .collect();
+
+    Roster { entries }
+}
+
+/// Parses an `RCD` message's `address}|{name` header and its `]\[address}|{normal` members,
+/// where `normal` is false for reversed locomotives.
+fn consist(entries: &str) -> Option<Consist> {
+    let mut entries = entries.split("]\\[");
+    let (address, name) = entries.next()?.split_once("}|{")?;
+    let mut consist = Consist::new(consist_address(address)?, name.to_string());
+    for entry in entries {
+        let (address, normal) = match entry.split_once("}|{") {
+            Some(member) => member,
+            None => continue,
+        };
+        if let Some(address) = consist_address(address) {
+            consist.add(address, normal != "true");
+        }
+    }
+
+    Some(consist)
+}
+
+/// Converts a consist message's `4805(L)` style address into `L4805`.
+fn consist_address(address: &str) -> Option<String> {
+    let (number, length) = address.strip_suffix(')')?.split_once('(')?;
+    let address = format!("{}{}", length, number);
+    is_address(&address).then_some(address)
+}
+
+/// Parses the `]\[name}|{user name}|{state` entries of a `PTL` message.
+fn turnouts(entries: &str) -> Vec<Turnout> {
+    entries
+        .split("]\\[")
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let mut fields = entry.split("}|{");
+            let name = fields.next()?.to_string();
+            let user_name = fields.next()?.to_string();
+            let state = TurnoutState::from_str(fields.next()?).unwrap();
+            Some(Turnout {
+                name,
+                user_name,
+                state,
+            })
+        })
+        .collect()
+}
+
+/// Parses the `]\[plural}|{singular]\[title}|{state...` entries of a `PTT` message.
+fn turnout_titles(entries: &str) -> Option<TurnoutTitles> {
+    let mut entries = entries.split("]\\[").filter(|entry| !entry.is_empty());
+    let (plural, singular) = entries.next()?.split_once("}|{")?;
+    let states = entries
+        .filter_map(|entry| {
+            let (title, state) = entry.split_once("}|{")?;
+            Some(TurnoutStateTitle {
+                state: TurnoutState::from_str(state).ok()?,
+                title: title.to_string(),
+            })
+        })
+        .collect();
+
+    Some(TurnoutTitles {
+        plural: plural.to_string(),
+        singular: singular.to_string(),
+        states,
+    })
+}
+
+fn function_num(num: &str) -> Option<FunctionNum> {
+    let num = FunctionNum::from_str(num).ok()?;
+    dcc::check_function(num).ok()
+}
+
+fn throttle_action(throttle: ThrottleId, address: String, msg: &str) -> Option<JmriUpdate> {
     if let Some(captures) = REGEXES.function.captures(msg) {
         let is_on = captures.name("on").unwrap().as_str() == "1";
-        let num = FunctionNum::from_str(captures.name("num").unwrap().as_str()).unwrap();
-        return Some(JmriUpdate::Function { is_on, num });
+        let num = function_num(captures.name("num").unwrap().as_str())?;
+        return Some(JmriUpdate::Function {
+            throttle,
+            address,
+            is_on,
+            num,
+        });
+    }
+
+    if let Some(captures) = REGEXES.function_mode.captures(msg) {
+        let momentary = captures.name("momentary").unwrap().as_str() == "1";
+        let num = function_num(captures.name("num").unwrap().as_str())?;
+        return Some(JmriUpdate::FunctionMode {
+            throttle,
+            address,
+            num,
+            momentary,
+        });
     }
 
     if let Some(captures) = REGEXES.velocity.captures(msg) {
-        let vel = VelocityValue::from_str(captures.name("v").unwrap().as_str()).unwrap();
-        return Some(JmriUpdate::Velocity(vel));
+        let value = VelocityValue::from_str(captures.name("v").unwrap().as_str()).unwrap();
+        return Some(JmriUpdate::Velocity {
+            throttle,
+            address,
+            value,
+            speed: None,
+        });
     }
 
     if let Some(captures) = REGEXES.direction.captures(msg) {
-        let dir = Direction::from_str(captures.name("d").unwrap().as_str()).unwrap();
-        return Some(JmriUpdate::Direction(dir));
+        let direction = Direction::from_str(captures.name("d").unwrap().as_str()).unwrap();
+        return Some(JmriUpdate::Direction {
+            throttle,
+            address,
+            direction,
+        });
+    }
+
+    if let Some(captures) = REGEXES.speed_steps.captures(msg) {
+        let mode = SpeedStepMode::from_str(captures.name("mode").unwrap().as_str()).ok()?;
+        return Some(JmriUpdate::SpeedSteps {
+            throttle,
+            address,
+            mode,
+        });
     }
 
     None
 }
+
+/// Parses the `]\[name}|{user name}|{state` entries of a `PRL` message.
+fn routes(entries: &str) -> Vec<Route> {
+    entries
+        .split("]\\[")
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let mut fields = entry.split("}|{");
+            let name = fields.next()?.to_string();
+            let user_name = fields.next()?.to_string();
+            let state = RouteState::from_str(fields.next().unwrap_or_default()).unwrap();
+            Some(Route {
+                name,
+                user_name,
+                state,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consist_address_converts_and_checks_addresses() {
+        let cases = [
+            ("4805(L)", Some("L4805")),
+            ("3(S)", Some("S3")),
+            ("10239(L)", Some("L10239")),
+            ("10240(L)", None),
+            ("128(S)", None),
+            ("3(X)", None),
+            ("3", None),
+            ("(L)", None),
+        ];
+        for (address, converted) in cases {
+            assert_eq!(
+                consist_address(address).as_deref(),
+                converted,
+                "{}",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn consist_reads_header_and_members() {
+        // Entries, then the address, name and members expected
+        let cases = [
+            (
+                r"4805(L)}|{Coal drag]\[4805(L)}|{true]\[3(S)}|{false",
+                Some(("L4805", "Coal drag", vec![("L4805", false), ("S3", true)])),
+            ),
+            (
+                r"12(S)}|{Pair]\[12(S)}|{true]\[junk]\[99999(L)}|{true",
+                Some(("S12", "Pair", vec![("S12", false)])),
+            ),
+            (r"12(S)}|{Empty", Some(("S12", "Empty", vec![]))),
+            (r"12(X)}|{Bad", None),
+            (r"no header", None),
+        ];
+        for (entries, expected) in cases {
+            let consist = consist(entries);
+            let consist = consist.as_ref().map(|consist| {
+                let members = consist
+                    .members
+                    .iter()
+                    .map(|member| (member.address.as_str(), member.reversed))
+                    .collect::<Vec<_>>();
+                (consist.address.as_str(), consist.name.as_str(), members)
+            });
+            assert_eq!(consist, expected, "{}", entries);
+        }
+    }
+
+    #[test]
+    fn roster_skips_malformed_entries() {
+        let cases = [
+            ("", vec![]),
+            (r"]\[Big Boy}|{4014}|{L", vec![("Big Boy", "L4014")]),
+            (
+                r"]\[Big Boy}|{4014}|{L]\[Switcher}|{3}|{S",
+                vec![("Big Boy", "L4014"), ("Switcher", "S3")],
+            ),
+            (
+                r"]\[No length}|{7]\[Bad number}|{x}|{S]\[Bad length}|{7}|{X]\[Ok}|{7}|{S",
+                vec![("Ok", "S7")],
+            ),
+        ];
+        for (entries, expected) in cases {
+            let roster = roster(entries);
+            let roster = roster
+                .entries
+                .iter()
+                .map(|entry| (entry.name.as_str(), entry.dcc_address()))
+                .collect::<Vec<_>>();
+            let expected = expected
+                .into_iter()
+                .map(|(name, address)| (name, address.to_string()))
+                .collect::<Vec<_>>();
+            assert_eq!(roster, expected, "{}", entries);
+        }
+    }
+
+    #[test]
+    fn roster_message_is_recognised() {
+        match jmri_message(r"RL2]\[Big Boy}|{4014}|{L]\[Switcher}|{3}|{S") {
+            Some(JmriUpdate::Roster(roster)) => assert_eq!(roster.entries.len(), 2),
+            _ => panic!("RL message not parsed as a roster"),
+        }
+    }
+
+    #[test]
+    fn turnouts_read_names_and_states() {
+        let cases = [
+            ("", vec![]),
+            (
+                r"]\[LT1}|{Yard lead}|{2]\[LT2}|{}|{4",
+                vec![
+                    ("LT1", "Yard lead", TurnoutState::Closed),
+                    ("LT2", "", TurnoutState::Thrown),
+                ],
+            ),
+            (
+                r"]\[LT3}|{Odd}|{9]\[LT4}|{No state",
+                vec![("LT3", "Odd", TurnoutState::Unknown)],
+            ),
+        ];
+        for (entries, expected) in cases {
+            let turnouts = turnouts(entries);
+            let turnouts = turnouts
+                .iter()
+                .map(|turnout| {
+                    let (name, user_name) = (turnout.name.as_str(), turnout.user_name.as_str());
+                    (name, user_name, turnout.state)
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(turnouts, expected, "{}", entries);
+        }
+    }
+
+    #[test]
+    fn turnout_titles_read_names_and_state_titles() {
+        let cases = [
+            (
+                r"]\[Turnouts}|{Turnout]\[Closed}|{2]\[Thrown}|{4",
+                Some((
+                    "Turnouts",
+                    "Turnout",
+                    vec![
+                        (TurnoutState::Closed, "Closed"),
+                        (TurnoutState::Thrown, "Thrown"),
+                    ],
+                )),
+            ),
+            (
+                r"]\[Points}|{Point]\[Normal}|{2",
+                Some(("Points", "Point", vec![(TurnoutState::Closed, "Normal")])),
+            ),
+            ("", None),
+            (r"]\[Turnouts", None),
+        ];
+        for (entries, expected) in cases {
+            let titles = turnout_titles(entries);
+            let titles = titles.as_ref().map(|titles| {
+                let states = titles
+                    .states
+                    .iter()
+                    .map(|state| (state.state, state.title.as_str()))
+                    .collect::<Vec<_>>();
+                (titles.plural.as_str(), titles.singular.as_str(), states)
+            });
+            assert_eq!(titles, expected, "{}", entries);
+        }
+    }
+
+    #[test]
+    fn turnout_messages_are_told_apart() {
+        assert!(matches!(
+            jmri_message(r"PTT]\[Turnouts}|{Turnout"),
+            Some(JmriUpdate::TurnoutTitles(_))
+        ));
+        assert!(matches!(
+            jmri_message(r"PTL]\[LT1}|{}|{2"),
+            Some(JmriUpdate::Turnouts(_))
+        ));
+        assert!(matches!(
+            jmri_message("PTA4LT1"),
+            Some(JmriUpdate::Turnout {
+                state: TurnoutState::Thrown,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn routes_read_names_and_states() {
+        let cases = [
+            ("", vec![]),
+            (
+                r"]\[IR1}|{Main}|{2]\[IR2}|{Siding}|{4",
+                vec![
+                    ("IR1", "Main", RouteState::Active),
+                    ("IR2", "Siding", RouteState::Inactive),
+                ],
+            ),
+            (
+                r"]\[IR3}|{No state]\[IR4",
+                vec![("IR3", "No state", RouteState::Unknown)],
+            ),
+        ];
+        for (entries, expected) in cases {
+            let routes = routes(entries);
+            let routes = routes
+                .iter()
+                .map(|route| (route.name.as_str(), route.user_name.as_str(), route.state))
+                .collect::<Vec<_>>();
+            assert_eq!(routes, expected, "{}", entries);
+        }
+    }
+
+    #[test]
+    fn function_labels_keep_positions() {
+        let cases = [
+            ("", vec![]),
+            (r"]\[Headlight", vec!["Headlight"]),
+            (
+                r"]\[Headlight]\[Bell]\[Horn",
+                vec!["Headlight", "Bell", "Horn"],
+            ),
+            (
+                r"]\[Headlight]\[]\[Horn]\[",
+                vec!["Headlight", "", "Horn", ""],
+            ),
+            ("Headlight", vec![]),
+        ];
+        for (labels, expected) in cases {
+            assert_eq!(function_labels(labels), expected, "{}", labels);
+        }
+    }
+
+    #[test]
+    fn function_label_message_is_recognised() {
+        match jmri_message(r"MTLL4014<;>]\[Headlight]\[Bell") {
+            Some(JmriUpdate::FunctionLabels {
+                address, labels, ..
+            }) => {
+                assert_eq!(address, "L4014");
+                assert_eq!(labels, vec!["Headlight", "Bell"]);
+            }
+            _ => panic!("L message not parsed as function labels"),
+        }
+    }
+}
@@ -1,59 +1,437 @@
+use crate::dcc::{
+    Direction, FunctionNum, PowerState, RouteState, SpeedStepMode, Throttle, ThrottleId,
+    TurnoutState, VelocityValue,
+};
+use crate::error::RequestError;
+use crate::jmri::JmriServerInfo;
+use crate::parse::JmriUpdate;
 use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::broadcast;
 use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::{Receiver, Sender};
-use tokio::task::JoinHandle;
+use tokio::sync::{mpsc, watch};
+use tokio::task::{JoinError, JoinHandle};
 use warp::ws::{Message, WebSocket, Ws};
 use warp::{Error, Filter};
 
-const ADDR: &str = "S67";
+pub type SessionId = usize;
 
+static NEXT_SESSION_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// The addresses each session follows, shared with the send handles so a lagging channel
+/// can't lose a subscription.
+pub type Subscriptions = Arc<Mutex<HashMap<SessionId, HashSet<String>>>>;
+
+/// Version of the client protocol below, announced to every session in `ServerEvent::Hello`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Chosen by the client to match each request with the `ServerEvent` answering it.
+pub type RequestId = u64;
+
+/// A request from a WebSocket client, e.g. `{"type":"query","id":1,"query":"roster"}`.
+/// Every request is answered by a `Success` or `Error` event carrying the same `id`.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientRequest {
+    /// Acquires, releases or drives a locomotive, or sets a turnout, route or track power, e.g.
+    /// `{"type":"command","id":2,"command":{"type":"speed","address":"S3","speed":0.5}}`.
+    /// Success means the command was sent; JMRI's confirmation arrives as an `Update`.
+    Command {
+        id: RequestId,
+        command: ClientCommand,
+    },
+    /// Asks for a snapshot of the bridge's state, answered in the `Success` event.
+    Query {
+        id: RequestId,
+        query: Query,
+    },
+    /// Emergency stops every locomotive the session owns, or with `all`, the whole layout.
+    EmergencyStop {
+        id: RequestId,
+        #[serde(default)]
+        all: bool,
+    },
+    /// Keeps the session alive when it has nothing else to send.
+    Ping {
+        id: RequestId,
+    },
+    /// Follows a locomotive's updates without driving it.
+    Subscribe {
+        id: RequestId,
+        address: String,
+    },
+    Unsubscribe {
+        id: RequestId,
+        address: String,
+    },
+}
+
+impl ClientRequest {
+    pub fn id(&self) -> RequestId {
+        match self {
+            ClientRequest::Command { id, .. }
+            | ClientRequest::Query { id, .. }
+            | ClientRequest::EmergencyStop { id, .. }
+            | ClientRequest::Ping { id }
+            | ClientRequest::Subscribe { id, .. }
+            | ClientRequest::Unsubscribe { id, .. } => *id,
+        }
+    }
+}
+
+/// What a client can ask of JMRI. Locomotive commands are sent on the session's own
+/// multi-throttle, which the server picks.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientCommand {
+    Acquire {
+        address: String,
+    },
+    Release {
+        address: String,
+    },
+    /// Takes an address another throttle holds, once JMRI has asked with a `Steal` update.
+    Steal {
+        address: String,
+    },
+    /// Forces a function on or off.
+    Function {
+        address: String,
+        num: FunctionNum,
+        is_on: bool,
+    },
+    /// Presses or releases a function button; a latching function toggles on each press.
+    FunctionButton {
+        address: String,
+        num: FunctionNum,
+        pressed: bool,
+    },
+    /// Makes a function momentary (held while pressed) or latching.
+    FunctionMode {
+        address: String,
+        num: FunctionNum,
+        momentary: bool,
+    },
+    /// A velocity from 0 to 126, or -1 to emergency stop.
+    Velocity {
+        address: String,
+        value: VelocityValue,
+    },
+    /// A normalized 0.0–1.0 speed, sent as the velocity for the decoder's speed steps.
+    Speed {
+        address: String,
+        speed: f32,
+    },
+    Direction {
+        address: String,
+        direction: Direction,
+    },
+    SpeedSteps {
+        address: String,
+        mode: SpeedStepMode,
+    },
+    EmergencyStop {
+        address: String,
+    },
+    Idle {
+        address: String,
+    },
+    /// Adds an owned locomotive to the session's ad-hoc consist.
+    ConsistAdd {
+        address: String,
+        #[serde(default)]
+        reversed: bool,
+    },
+    /// Removes a locomotive from the session's ad-hoc consist.
+    ConsistRemove {
+        address: String,
+    },
+    /// Closes or throws a turnout.
+    Turnout {
+        name: String,
+        state: TurnoutState,
+    },
+    ToggleTurnout {
+        name: String,
+    },
+    /// Sets a route.
+    Route {
+        name: String,
+    },
+    Power {
+        state: PowerState,
+    },
+}
+
+impl From<ClientCommand> for JmriUpdate {
+    fn from(command: ClientCommand) -> Self {
+        let throttle = ThrottleId::default();
+        match command {
+            ClientCommand::Acquire { address } => JmriUpdate::Acquire { throttle, address },
+            ClientCommand::Release { address } => JmriUpdate::Release { throttle, address },
+            ClientCommand::Steal { address } => JmriUpdate::Steal { throttle, address },
+            ClientCommand::Function {
+                address,
+                num,
+                is_on,
+            } => JmriUpdate::Function {
+                throttle,
+                address,
+                num,
+                is_on,
+            },
+            ClientCommand::FunctionButton {
+                address,
+                num,
+                pressed,
+            } => JmriUpdate::FunctionButton {
+                throttle,
+                address,
+                num,
+                pressed,
+            },
+            ClientCommand::FunctionMode {
+                address,
+                num,
+                momentary,
+            } => JmriUpdate::FunctionMode {
+                throttle,
+                address,
+                num,
+                momentary,
+            },
+            ClientCommand::Velocity { address, value } => JmriUpdate::Velocity {
+                throttle,
+                address,
+                value,
+                speed: None,
+            },
+            ClientCommand::Speed { address, speed } => JmriUpdate::Speed {
+                throttle,
+                address,
+                speed,
+            },
+            ClientCommand::Direction { address, direction } => JmriUpdate::Direction {
+                throttle,
+                address,
+                direction,
+            },
+            ClientCommand::SpeedSteps { address, mode } => JmriUpdate::SpeedSteps {
+                throttle,
+                address,
+                mode,
+            },
+            ClientCommand::EmergencyStop { address } => {
+                JmriUpdate::EmergencyStop { throttle, address }
+            }
+            ClientCommand::Idle { address } => JmriUpdate::Idle { throttle, address },
+            ClientCommand::ConsistAdd { address, reversed } => JmriUpdate::ConsistAdd {
+                throttle,
+                address,
+                reversed,
+            },
+            ClientCommand::ConsistRemove { address } => {
+                JmriUpdate::ConsistRemove { throttle, address }
+            }
+            ClientCommand::Turnout { name, state } => JmriUpdate::Turnout { name, state },
+            ClientCommand::ToggleTurnout { name } => JmriUpdate::ToggleTurnout { name },
+            ClientCommand::Route { name } => JmriUpdate::Route {
+                name,
+                state: RouteState::Active,
+            },
+            ClientCommand::Power { state } => JmriUpdate::Power(state),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Query {
+    /// Every locomotive the session owns
+    Throttles,
+    Roster,
+    Consists,
+    Turnouts,
+    /// How JMRI titles turnouts and their states for display
+    TurnoutTitles,
+    Routes,
+    Power,
+    Status,
+    /// The current scaled layout time, an error until JMRI reports a fast clock
+    Time,
+}
+
+/// Bridge status reported to clients on request
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Status {
+    /// JMRI's heartbeat timeout in seconds, if it requires one
+    pub heartbeat: Option<u64>,
+    pub jmri_online: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryResult {
+    Throttles(Vec<Throttle>),
+    Status(Status),
+    Update(JmriUpdate),
+}
+
+/// Everything the server sends to WebSocket clients.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// Sent once a session connects.
+    Hello { version: u32, session: SessionId },
+    /// The request with `id` was carried out, with its result if it has one.
+    Success {
+        id: RequestId,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        result: Option<QueryResult>,
+    },
+    /// The request with `id` was refused, or couldn't be read at all if `id` is missing.
+    /// `message` describes `error` for display.
+    Error {
+        id: Option<RequestId>,
+        error: RequestError,
+        message: String,
+    },
+    /// Something changed on the layout or in the bridge.
+    Update { update: JmriUpdate },
+}
+
+/// Messages to sessions travel over a broadcast channel, while each session's requests, opening
+/// and closing reach the server over a queue that never drops them.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum WSMessage {
-    Send { address: String, message: String },
-    Receive { address: String, message: String },
+    /// Sent to every session subscribed to `address`.
+    Send {
+        address: String,
+        message: String,
+    },
+    /// Sent to a single session.
+    Reply {
+        session: SessionId,
+        message: String,
+    },
+    /// Sent to every session.
+    Broadcast {
+        message: String,
+    },
+    Receive {
+        session: SessionId,
+        message: String,
+    },
+    /// Emitted once a session's socket has opened.
+    Opened {
+        session: SessionId,
+    },
+    /// Emitted once a session's socket has closed.
+    Closed {
+        session: SessionId,
+    },
 }
 
-#[allow(dead_code)]
 pub struct WSListener {
     listener_handle: JoinHandle<()>,
     channel: Channel,
+    events: Option<mpsc::Receiver<WSMessage>>,
+    subscriptions: Subscriptions,
 }
 
 type Channel = Sender<WSMessage>;
+type Events = mpsc::Sender<WSMessage>;
+
+/// Messages waiting to be written to the sessions. A session that falls this far behind misses
+/// the oldest of them, so leave room for the updates JMRI sends when a locomotive is acquired.
+const CHANNEL_CAPACITY: usize = 256;
+/// Requests waiting for the server. Once it is full, reading from the sessions waits.
+const EVENTS_CAPACITY: usize = 256;
 
 impl WSListener {
-    pub fn new(address: SocketAddr) -> Self {
-        let (channel, _) = broadcast::channel::<WSMessage>(30);
-        let listener_handle = make_ws_handle(address, channel.clone());
+    /// Serves WebSocket sessions on `/ws`, along with `/health` and JMRI's `server_info` on `/info`.
+    /// Sessions that send nothing, not even a ping, for `idle_timeout` are closed.
+    pub fn new(
+        address: SocketAddr,
+        server_info: watch::Receiver<JmriServerInfo>,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        let (channel, _) = broadcast::channel::<WSMessage>(CHANNEL_CAPACITY);
+        let (events_tx, events) = mpsc::channel::<WSMessage>(EVENTS_CAPACITY);
+        let subscriptions: Subscriptions = Arc::new(Mutex::new(HashMap::new()));
+        let listener_handle = make_ws_handle(
+            address,
+            channel.clone(),
+            events_tx,
+            subscriptions.clone(),
+            server_info,
+            idle_timeout,
+        );
 
         WSListener {
             listener_handle,
             channel,
+            events: Some(events),
+            subscriptions,
         }
     }
 
+    /// Waits for the server to stop, which only happens if it fails, e.g. to bind its address.
+    pub async fn finished(&mut self) -> Result<(), JoinError> {
+        (&mut self.listener_handle).await
+    }
+
     pub fn clone_channel(&mut self) -> Channel {
         self.channel.clone()
     }
 
-    pub fn subscribe(&mut self) -> Receiver<WSMessage> {
-        self.channel.subscribe()
+    /// Every session's `Opened`, `Receive` and `Closed` messages, in order and without gaps.
+    /// There is only one receiver, so this returns `None` once taken.
+    pub fn take_receiver(&mut self) -> Option<mpsc::Receiver<WSMessage>> {
+        self.events.take()
+    }
+
+    /// Add or remove a session's addresses here to choose which `WSMessage::Send`s reach it.
+    /// A session's entry is dropped once its socket closes.
+    pub fn subscriptions(&self) -> Subscriptions {
+        self.subscriptions.clone()
     }
 }
 
-fn make_ws_handle(address: SocketAddr, channel: Sender<WSMessage>) -> JoinHandle<()> {
+fn make_ws_handle(
+    address: SocketAddr,
+    channel: Sender<WSMessage>,
+    events: Events,
+    subscriptions: Subscriptions,
+    server_info: watch::Receiver<JmriServerInfo>,
+    idle_timeout: Option<Duration>,
+) -> JoinHandle<()> {
     let channel = warp::any().map(move || channel.clone());
+    let events = warp::any().map(move || events.clone());
+    let subscriptions = warp::any().map(move || subscriptions.clone());
     let health_route = warp::path("health").map(|| "OK");
+    let info_route = warp::path("info").map(move || warp::reply::json(&*server_info.borrow()));
     let ws_route = warp::path("ws")
         .and(warp::ws())
         .and(channel)
-        .map(|ws: Ws, channel: Channel| ws.on_upgrade(move |ws| handle_ws_connection(ws, channel)));
+        .and(events)
+        .and(subscriptions)
+        .map(
+            move |ws: Ws, channel: Channel, events: Events, subscriptions: Subscriptions| {
+                ws.on_upgrade(move |ws| {
+                    handle_ws_connection(ws, channel, events, subscriptions, idle_timeout)
+                })
+            },
+        );
 
-    let routes = health_route.or(ws_route);
+    let routes = health_route.or(info_route).or(ws_route);
 
     tokio::spawn(async move {
         warp::serve(routes).run(address).await;
@@ -61,20 +439,32 @@ fn make_ws_handle(address: SocketAddr, channel: Sender<WSMessage>) -> JoinHandle
 }
 
 fn make_ws_send_handle(
+    session: SessionId,
     mut receiver: Receiver<WSMessage>,
     mut tx: SplitSink<WebSocket, Message>,
+    subscriptions: Subscriptions,
 ) -> JoinHandle<Result<(), Error>> {
     tokio::spawn(async move {
         loop {
             let msg = match receiver.recv().await {
                 Ok(msg) => match msg {
                     WSMessage::Send { address, message } => {
-                        if !address.eq(ADDR) {
+                        let subscribed = subscriptions
+                            .lock()
+                            .unwrap()
+                            .get(&session)
+                            .is_some_and(|addresses| addresses.contains(&address));
+                        if !subscribed {
                             continue;
                         }
                         message
                     }
-                    WSMessage::Receive { .. } => continue,
+                    WSMessage::Reply {
+                        session: s,
+                        message,
+                    } if s == session => message,
+                    WSMessage::Broadcast { message } => message,
+                    _ => continue,
                 },
                 Err(e) => match e {
                     RecvError::Closed => break,
@@ -93,11 +483,26 @@ fn make_ws_send_handle(
 }
 
 fn make_ws_receive_handle(
-    sender: Sender<WSMessage>,
+    session: SessionId,
+    events: Events,
     mut rx: SplitStream<WebSocket>,
+    idle_timeout: Option<Duration>,
 ) -> JoinHandle<()> {
     tokio::spawn(async move {
-        while let Some(msg) = rx.next().await {
+        loop {
+            let msg = match idle_timeout {
+                Some(idle_timeout) => match tokio::time::timeout(idle_timeout, rx.next()).await {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                },
+                None => rx.next().await,
+            };
+            let msg = match msg {
+                Some(msg) => msg,
+                None => break,
+            };
+
+            // Pings count as activity but carry nothing to relay
             let message = match msg {
                 Ok(msg) => {
                     if let Ok(s) = msg.to_str() {
@@ -109,23 +514,39 @@ fn make_ws_receive_handle(
                 Err(_e) => continue,
             };
 
-            let message = WSMessage::Receive {
-                address: ADDR.to_string(),
-                message,
-            };
-
-            let _ = sender.send(message);
+            if events
+                .send(WSMessage::Receive { session, message })
+                .await
+                .is_err()
+            {
+                break;
+            }
         }
     })
 }
 
-async fn handle_ws_connection(ws: WebSocket, channel: Channel) {
+async fn handle_ws_connection(
+    ws: WebSocket,
+    channel: Channel,
+    events: Events,
+    subscriptions: Subscriptions,
+    idle_timeout: Option<Duration>,
+) {
+    let session = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
     let (ws_tx, ws_rx) = ws.split();
 
-    let send_handle = make_ws_send_handle(channel.subscribe(), ws_tx);
-    let receive_handle = make_ws_receive_handle(channel.clone(), ws_rx);
+    let mut send_handle =
+        make_ws_send_handle(session, channel.subscribe(), ws_tx, subscriptions.clone());
+    // Announced before the receive handle starts so the server sees it ahead of any request
+    let _ = events.send(WSMessage::Opened { session }).await;
+    let mut receive_handle = make_ws_receive_handle(session, events.clone(), ws_rx, idle_timeout);
+
+    // Whichever half finishes first ends the session
+    tokio::select! {
+        _ = &mut send_handle => receive_handle.abort(),
+        _ = &mut receive_handle => send_handle.abort(),
+    }
 
-    // TODO: Fall-through on failures or breaks
-    let _ = send_handle.await;
-    let _ = receive_handle.await;
+    subscriptions.lock().unwrap().remove(&session);
+    let _ = events.send(WSMessage::Closed { session }).await;
 }
@@ -5,6 +5,7 @@ use std::fmt::{Debug, Display, Formatter};
 use std::fs;
 use std::net::SocketAddr;
 use std::path::Path;
+use std::time::Duration;
 use toml::Value;
 use uuid::Uuid;
 
@@ -30,10 +31,78 @@ impl Display for ConfigError {
 
 impl Error for ConfigError {}
 
+/// How a closed session's locomotives are brought to a stand.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeadManAction {
+    /// Set the speed to zero, letting the decoder's momentum slow the train
+    Stop,
+    /// Emergency stop immediately
+    EmergencyStop,
+}
+
+/// What happens to the locomotives of a session that closes or goes quiet.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct DeadManPolicy {
+    pub action: DeadManAction,
+    /// How long to keep the stopped locomotives before releasing them, if at all
+    pub release_after: Option<Duration>,
+    /// Close sessions that send nothing, not even a ping, for this long
+    pub ping_timeout: Option<Duration>,
+}
+
+impl DeadManPolicy {
+    fn from_values(values: &Value) -> Result<Self, ConfigError> {
+        let action = match values.get("dead_man_action").map(|a| a.as_str()) {
+            None | Some(Some("stop")) => DeadManAction::Stop,
+            Some(Some("estop")) => DeadManAction::EmergencyStop,
+            Some(_) => {
+                return Err(ConfigError::new(
+                    "'dead_man_action' must be \"stop\" or \"estop\"".to_string(),
+                ))
+            }
+        };
+
+        let release = match values.get("dead_man_release") {
+            None => true,
+            Some(release) => release.as_bool().ok_or_else(|| {
+                ConfigError::new("'dead_man_release' must be true or false".to_string())
+            })?,
+        };
+        let release_after = if release {
+            Some(seconds(values, "dead_man_release_after")?.unwrap_or_default())
+        } else {
+            None
+        };
+        // A zero timeout would close every session straight away, so it disables the check
+        let ping_timeout = seconds(values, "ping_timeout")?.filter(|timeout| !timeout.is_zero());
+
+        Ok(DeadManPolicy {
+            action,
+            release_after,
+            ping_timeout,
+        })
+    }
+}
+
+/// Reads an optional, non-negative number of seconds.
+fn seconds(values: &Value, key: &str) -> Result<Option<Duration>, ConfigError> {
+    let value = match values.get(key) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    value
+        .as_integer()
+        .and_then(|s| u64::try_from(s).ok())
+        .map(|s| Some(Duration::from_secs(s)))
+        .ok_or_else(|| ConfigError::new(format!("'{}' must be a number of seconds", key)))
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     pub jmri_host: SocketAddr,
     pub server_host: SocketAddr,
+    pub dead_man: DeadManPolicy,
 
     #[serde(skip_serializing, skip_deserializing)]
     pub uuid: String,
@@ -71,9 +140,12 @@ impl Config {
             .and_then(|host| host.parse::<SocketAddr>().ok())
             .unwrap_or(*DEFAULT_WEBSOCKET_HOST);
 
+        let dead_man = DeadManPolicy::from_values(&values)?;
+
         Ok(Config {
             jmri_host,
             server_host,
+            dead_man,
             uuid: Uuid::new_v4().to_string(),
         })
     }
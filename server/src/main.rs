@@ -2,25 +2,49 @@
 extern crate log;
 extern crate pretty_env_logger;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crate::config::Config;
-use common::dcc::{DccTime, Direction, Throttle};
-use common::jmri::{JmriMessage, JmriStream};
+use crate::config::{Config, DeadManAction, DeadManPolicy};
+use common::dcc;
+use common::dcc::{
+    ClockEvent, Consist, DccTime, PowerState, Roster, Route, RouteState, Throttle, ThrottleId,
+    Turnout, TurnoutState, TurnoutTitles,
+};
+use common::error::RequestError;
+use common::jmri::{JmriMessage, JmriSender, JmriStream, RETURN, SUPPORTED_VERSION};
 use common::parse;
-use common::parse::JmriUpdate;
-use common::server::{WSListener, WSMessage};
-use tokio::sync::broadcast::error::RecvError;
+use common::parse::{AlertLevel, JmriUpdate};
+use common::server::{
+    ClientRequest, Query, QueryResult, RequestId, ServerEvent, SessionId, Status, Subscriptions,
+    WSListener, WSMessage, PROTOCOL_VERSION,
+};
+use tokio::sync::broadcast;
+use tokio::sync::Notify;
+use tokio::task::JoinError;
 
 mod config;
 
-// TODO: Base this on an address request rather than using this hard-code test value
-pub const ADDR: &str = "S67";
-
 type ThrottlesState = Arc<Mutex<HashMap<String, Throttle>>>;
-type TimeState = Arc<Mutex<DccTime>>;
+/// `None` until JMRI reports a fast clock
+type TimeState = Arc<Mutex<Option<DccTime>>>;
+type RosterState = Arc<Mutex<Roster>>;
+/// Consists defined in JMRI, by consist address
+type ConsistsState = Arc<Mutex<HashMap<String, Consist>>>;
+type TurnoutsState = Arc<Mutex<HashMap<String, Turnout>>>;
+type TurnoutTitlesState = Arc<Mutex<TurnoutTitles>>;
+type RoutesState = Arc<Mutex<HashMap<String, Route>>>;
+type TrackPowerState = Arc<Mutex<PowerState>>;
+type StatusState = Arc<Mutex<Status>>;
+
+/// Which session owns each acquired locomotive address
+type OwnersState = Arc<Mutex<HashMap<String, SessionId>>>;
+/// The JMRI multi-throttle each session drives its locomotives through
+type SessionThrottlesState = Arc<Mutex<HashMap<SessionId, ThrottleId>>>;
+/// The ad-hoc consist each session has built from its locomotives
+type SessionConsistsState = Arc<Mutex<HashMap<SessionId, Consist>>>;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -28,69 +52,357 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let config = Config::get()?;
 
-    let throttles: ThrottlesState = Arc::new(Mutex::new(HashMap::from([(
-        ADDR.to_string(),
-        Throttle::new(ADDR.to_string()),
-    )])));
-    let time: TimeState = Arc::new(Mutex::new(DccTime::default()));
+    let throttles: ThrottlesState = Arc::new(Mutex::new(HashMap::new()));
+    let time: TimeState = Arc::new(Mutex::new(None));
+    let roster: RosterState = Arc::new(Mutex::new(Roster::default()));
+    let consists: ConsistsState = Arc::new(Mutex::new(HashMap::new()));
+    let turnouts: TurnoutsState = Arc::new(Mutex::new(HashMap::new()));
+    let turnout_titles: TurnoutTitlesState = Arc::new(Mutex::new(TurnoutTitles::default()));
+    let routes: RoutesState = Arc::new(Mutex::new(HashMap::new()));
+    let power: TrackPowerState = Arc::new(Mutex::new(PowerState::default()));
+    let status: StatusState = Arc::new(Mutex::new(Status::default()));
+    let owners: OwnersState = Arc::new(Mutex::new(HashMap::new()));
+    let session_throttles: SessionThrottlesState = Arc::new(Mutex::new(HashMap::new()));
+    let session_consists: SessionConsistsState = Arc::new(Mutex::new(HashMap::new()));
 
-    let mut jmri_stream = match JmriStream::new(config.jmri_host).await {
-        Ok(stream) => stream,
-        Err(e) => panic!("Error connecting to JMRI: {}", e),
-    };
+    let handshake = vec![format!("HU{}", config.uuid), "NRusty".to_string()];
+    let mut jmri_stream = JmriStream::new(config.jmri_host, handshake);
 
-    let mut ws_listener = WSListener::new(config.server_host);
+    let mut ws_listener = WSListener::new(
+        config.server_host,
+        jmri_stream.server_info(),
+        config.dead_man.ping_timeout,
+    );
+    let mut ws_chann_rx = ws_listener
+        .take_receiver()
+        .expect("nothing else reads the sessions' requests");
+    let subscriptions = ws_listener.subscriptions();
 
     let jmri_sender = jmri_stream.clone_sender();
-    let messages = [
-        format!("HU{}", config.uuid),
-        "NRusty".to_string(),
-        format!("MT+{}<;>{}", ADDR, ADDR),
-    ];
-    for message in messages {
-        let msg = JmriMessage::Send(message);
-        jmri_sender.send(msg).unwrap();
-    }
 
     // TODO: Better way around creating a bunch of vars?
-    let mut jmri_listener = jmri_stream.subscribe();
+    let mut jmri_listener = jmri_stream
+        .take_receiver()
+        .expect("nothing else reads from JMRI");
+    status.lock().unwrap().jmri_online = jmri_stream.is_connected();
     let listener_throttle = throttles.clone();
     let listener_time = time.clone();
+    let clock_changed = Arc::new(Notify::new());
+    let listener_clock_changed = clock_changed.clone();
+    let listener_roster = roster.clone();
+    let listener_consists = consists.clone();
+    let listener_turnouts = turnouts.clone();
+    let listener_turnout_titles = turnout_titles.clone();
+    let listener_routes = routes.clone();
+    let listener_power = power.clone();
+    let listener_status = status.clone();
+    let listener_owners = owners.clone();
+    let listener_session_throttles = session_throttles.clone();
+    let listener_subscriptions = subscriptions.clone();
+    let listener_session_consists = session_consists.clone();
+    let listener_jmri_sender = jmri_sender.clone();
     let jmri_ws_sender = ws_listener.clone_channel();
-    let jmri_listen_handle = tokio::spawn(async move {
+    let mut jmri_listen_handle = tokio::spawn(async move {
         loop {
             let msg = match jmri_listener.recv().await {
-                Ok(msg) => match msg {
-                    JmriMessage::Send(_) => continue,
-                    JmriMessage::Receive(msg) => msg,
-                },
-                Err(e) => match e {
-                    RecvError::Closed => {
-                        error!("Listener channel closed: {}", e);
-                        break;
-                    }
-                    RecvError::Lagged(_) => continue,
-                },
+                Some(msg) => msg,
+                None => {
+                    error!("JMRI stream closed");
+                    break;
+                }
             };
 
-            debug!("Message: {}", msg);
+            let mut update = match msg {
+                JmriMessage::Send(_) => continue,
+                JmriMessage::Receive(msg) => {
+                    debug!("Message: {}", msg);
+                    match parse::jmri_message(msg.as_str()) {
+                        Some(update) => update,
+                        None => continue,
+                    }
+                }
+                JmriMessage::Connected => JmriUpdate::Online(true),
+                JmriMessage::Disconnected(reason) => {
+                    warn!("JMRI connection ended: {}", reason);
+                    JmriUpdate::Online(false)
+                }
+            };
 
-            let mut throttle = listener_throttle.lock().unwrap();
-            let throttle = throttle.get_mut(ADDR).unwrap();
-            let mut time = listener_time.lock().unwrap();
+            // Set for a release that no longer concerns the locomotive's owner
+            let mut stale = false;
+            {
+                let mut throttles = listener_throttle.lock().unwrap();
+                let mut time = listener_time.lock().unwrap();
 
-            if let Some(update) = parse::jmri_message(msg.as_str()) {
                 match update.clone() {
-                    JmriUpdate::Function { num, is_on } => throttle.set_func(num, is_on),
-                    JmriUpdate::Velocity(value) => throttle.set_vel(value),
-                    JmriUpdate::Direction(dir) => throttle.set_dir(dir),
-                    JmriUpdate::Time { timestamp, scale } => time.update(timestamp, scale),
-                };
-                let ws_msg = WSMessage::Send {
-                    address: ADDR.to_string(),
-                    message: serde_json::to_string(&update).unwrap(),
+                    JmriUpdate::Acquire { throttle, address } => {
+                        info!("Acquired {} on M{}", address, throttle);
+                        // Keep any existing state when re-acquiring after a reconnection,
+                        // but not what another multi-throttle left behind
+                        match throttles.get(&address) {
+                            Some(held) if held.get_throttle() == throttle => {}
+                            _ => {
+                                throttles.insert(address.clone(), Throttle::new(throttle, address));
+                            }
+                        }
+                    }
+                    JmriUpdate::Release { throttle, address } => {
+                        let current = throttles
+                            .get(&address)
+                            .is_some_and(|held| held.get_throttle() == throttle);
+                        if current {
+                            info!("Released {} from M{}", address, throttle);
+                            throttles.remove(&address);
+                        }
+                        // A late confirmation mustn't touch an owner now driving it on
+                        // another multi-throttle
+                        let owner = listener_owners.lock().unwrap().get(&address).copied();
+                        let owner_throttle = owner.and_then(|session| {
+                            listener_session_throttles
+                                .lock()
+                                .unwrap()
+                                .get(&session)
+                                .copied()
+                        });
+                        stale = match owner_throttle {
+                            Some(owner_throttle) => owner_throttle != throttle,
+                            None => !current,
+                        };
+                        if stale {
+                            debug!("Ignoring stale release of {} from M{}", address, throttle);
+                        }
+                    }
+                    JmriUpdate::Function {
+                        address,
+                        num,
+                        is_on,
+                        ..
+                    } => {
+                        if let Some(throttle) = throttles.get_mut(&address) {
+                            throttle.set_func(num, is_on);
+                        }
+                    }
+                    JmriUpdate::Velocity { address, value, .. } => {
+                        if let Some(throttle) = throttles.get_mut(&address) {
+                            throttle.set_vel(value);
+                        }
+                    }
+                    JmriUpdate::Direction {
+                        address, direction, ..
+                    } => {
+                        if let Some(throttle) = throttles.get_mut(&address) {
+                            throttle.set_dir(direction);
+                        }
+                    }
+                    JmriUpdate::SpeedSteps { address, mode, .. } => {
+                        if let Some(throttle) = throttles.get_mut(&address) {
+                            throttle.set_speed_steps(mode);
+                        }
+                    }
+                    JmriUpdate::FunctionLabels {
+                        address, labels, ..
+                    } => {
+                        if let Some(throttle) = throttles.get_mut(&address) {
+                            throttle.set_func_labels(labels);
+                        }
+                    }
+                    JmriUpdate::FunctionMode {
+                        address,
+                        num,
+                        momentary,
+                        ..
+                    } => {
+                        if let Some(throttle) = throttles.get_mut(&address) {
+                            throttle.set_momentary(num, momentary);
+                        }
+                    }
+                    JmriUpdate::Speed { .. }
+                    | JmriUpdate::FunctionButton { .. }
+                    | JmriUpdate::EmergencyStop { .. }
+                    | JmriUpdate::Idle { .. } => {}
+                    JmriUpdate::Steal { address, .. } => {
+                        info!("{} is in use by another throttle", address);
+                    }
+                    JmriUpdate::Time { timestamp, scale } => {
+                        let time = time.get_or_insert_with(DccTime::default);
+                        if let Some(event) = time.update(timestamp, scale) {
+                            info!("Fast clock {:?}", event);
+                            let update = JmriUpdate::Clock(event);
+                            let message =
+                                serde_json::to_string(&ServerEvent::Update { update }).unwrap();
+                            let _ = jmri_ws_sender.send(WSMessage::Broadcast { message });
+                        }
+                        listener_clock_changed.notify_one();
+                    }
+                    JmriUpdate::Clock(_) => {}
+                    JmriUpdate::Roster(roster) => {
+                        info!("Received roster of {} entries", roster.entries.len());
+                        *listener_roster.lock().unwrap() = roster;
+                    }
+                    JmriUpdate::ConsistCount(count) => {
+                        info!("Receiving {} consists", count);
+                        listener_consists.lock().unwrap().clear();
+                    }
+                    JmriUpdate::Consist(consist) => {
+                        listener_consists
+                            .lock()
+                            .unwrap()
+                            .insert(consist.address.clone(), consist);
+                    }
+                    JmriUpdate::Consists(_)
+                    | JmriUpdate::ConsistAdd { .. }
+                    | JmriUpdate::ConsistRemove { .. } => {}
+                    JmriUpdate::Turnouts(list) => {
+                        let mut turnouts = listener_turnouts.lock().unwrap();
+                        *turnouts = list
+                            .into_iter()
+                            .map(|turnout| (turnout.name.clone(), turnout))
+                            .collect();
+                    }
+                    JmriUpdate::TurnoutTitles(titles) => {
+                        *listener_turnout_titles.lock().unwrap() = titles;
+                    }
+                    JmriUpdate::Turnout { name, state } => {
+                        if let Some(turnout) = listener_turnouts.lock().unwrap().get_mut(&name) {
+                            turnout.state = state;
+                        }
+                    }
+                    JmriUpdate::ToggleTurnout { .. } => {}
+                    JmriUpdate::Routes(list) => {
+                        let mut routes = listener_routes.lock().unwrap();
+                        *routes = list
+                            .into_iter()
+                            .map(|route| (route.name.clone(), route))
+                            .collect();
+                    }
+                    JmriUpdate::Route { name, state } => {
+                        if let Some(route) = listener_routes.lock().unwrap().get_mut(&name) {
+                            route.state = state;
+                        }
+                    }
+                    JmriUpdate::Power(state) => {
+                        info!("Track power {:?}", state);
+                        *listener_power.lock().unwrap() = state;
+                    }
+                    JmriUpdate::Heartbeat(seconds) => {
+                        info!("JMRI heartbeat timeout is {}s", seconds);
+                        listener_status.lock().unwrap().heartbeat =
+                            Some(seconds).filter(|s| *s > 0);
+                    }
+                    JmriUpdate::Alert { level, text } => match level {
+                        AlertLevel::Alert => warn!("JMRI alert: {}", text),
+                        AlertLevel::Info => info!("JMRI info: {}", text),
+                    },
+                    JmriUpdate::Online(online) => {
+                        let mut status = listener_status.lock().unwrap();
+                        if online {
+                            // Nobody is driving a loco without an owner, so leave it released
+                            let owners = listener_owners.lock().unwrap();
+                            throttles.retain(|address, _| owners.contains_key(address));
+                            info!("Connected to JMRI, restoring {} throttles", throttles.len());
+                            let replay = throttles.values().flat_map(replay_throttle).collect();
+                            if let Some(message) = batch(replay) {
+                                let _ = listener_jmri_sender.send(message);
+                            }
+                        } else {
+                            status.heartbeat = None;
+                        }
+                        status.jmri_online = online;
+                    }
                 };
-                jmri_ws_sender.send(ws_msg).unwrap();
+
+                // Clients get the velocity normalized for the decoder's speed steps too
+                if let JmriUpdate::Velocity { address, speed, .. } = &mut update {
+                    *speed = throttles.get(address).map(Throttle::get_speed);
+                }
+            }
+            if stale {
+                continue;
+            }
+
+            let message = serde_json::to_string(&ServerEvent::Update {
+                update: update.clone(),
+            })
+            .unwrap();
+            let ws_msg = match update.address() {
+                Some(address) => WSMessage::Send {
+                    address: address.to_string(),
+                    message,
+                },
+                None => WSMessage::Broadcast { message },
+            };
+            let _ = jmri_ws_sender.send(ws_msg);
+
+            // Only drop the owner's subscription once it has seen the release
+            if let JmriUpdate::Release { address, .. } = update {
+                if let Some(session) = listener_owners.lock().unwrap().remove(&address) {
+                    let mut session_consists = listener_session_consists.lock().unwrap();
+                    if let Some(consist) = session_consists.get_mut(&session) {
+                        consist.remove(&address);
+                        if consist.is_empty() {
+                            session_consists.remove(&session);
+                        }
+                    }
+                    unsubscribe(&listener_subscriptions, session, &address);
+                }
+            }
+        }
+    });
+
+    // Tick every layout minute so clients needn't run their own fast clock
+    let clock_time = time.clone();
+    let clock_ws_sender = ws_listener.clone_channel();
+    let mut clock_handle = tokio::spawn(async move {
+        // Wait for JMRI to report a fast clock before ticking
+        clock_changed.notified().await;
+
+        let mut last_tick = None;
+        loop {
+            let next = clock_time
+                .lock()
+                .unwrap()
+                .as_ref()
+                .and_then(DccTime::until_next_minute);
+            let (wait, minute) = match next {
+                Some(next) => next,
+                None => {
+                    clock_changed.notified().await;
+                    continue;
+                }
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {
+                    if last_tick == Some(minute) {
+                        continue;
+                    }
+                    last_tick = Some(minute);
+
+                    let update = JmriUpdate::Clock(ClockEvent::Tick(minute));
+                    let message = serde_json::to_string(&ServerEvent::Update { update }).unwrap();
+                    let _ = clock_ws_sender.send(WSMessage::Broadcast { message });
+                }
+                _ = clock_changed.notified() => {}
+            }
+        }
+    });
+
+    // Warn when JMRI announces a protocol version we may not understand
+    let mut server_info = jmri_stream.server_info();
+    let mut version_handle = tokio::spawn(async move {
+        let mut last_version = None;
+        while server_info.changed().await.is_ok() {
+            let info = server_info.borrow().clone();
+            if info.version == last_version {
+                continue;
+            }
+            last_version = info.version.clone();
+
+            if let Some(version) = info.version.as_ref().filter(|_| !info.is_supported()) {
+                warn!(
+                    "JMRI speaks WiThrottle {}, only version {}.x is supported",
+                    version, SUPPORTED_VERSION
+                );
             }
         }
     });
@@ -98,95 +410,871 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // TODO: Better way around creating a bunch of vars?
     let ws_chann_tx = ws_listener.clone_channel();
     let ws_throttles = throttles.clone();
+    let ws_roster = roster.clone();
+    let ws_consists = consists.clone();
+    let ws_turnouts = turnouts.clone();
+    let ws_turnout_titles = turnout_titles.clone();
+    let ws_routes = routes.clone();
+    let ws_power = power.clone();
+    let ws_status = status.clone();
+    let ws_time = time.clone();
+    let ws_owners = owners.clone();
+    let ws_subscriptions = subscriptions.clone();
+    let ws_session_throttles = session_throttles.clone();
+    let ws_session_consists = session_consists.clone();
     let ws_jmri_sender = jmri_sender.clone();
-    let ws_listen_handle = tokio::spawn(async move {
-        let mut ws_chann_rx = ws_chann_tx.subscribe();
+    let ws_dead_man = config.dead_man;
+    let mut ws_listen_handle = tokio::spawn(async move {
         loop {
-            let msg = match ws_chann_rx.recv().await {
-                Ok(msg) => match msg {
-                    WSMessage::Send { .. } => continue,
-                    WSMessage::Receive { message, .. } => message,
-                },
-                Err(e) => match e {
-                    RecvError::Closed => break,
-                    RecvError::Lagged(_) => continue,
+            let (session, msg) = match ws_chann_rx.recv().await {
+                Some(msg) => match msg {
+                    WSMessage::Receive { session, message } => (session, message),
+                    WSMessage::Opened { session } => {
+                        let hello = ServerEvent::Hello {
+                            version: PROTOCOL_VERSION,
+                            session,
+                        };
+                        reply(&ws_chann_tx, session, &hello);
+                        continue;
+                    }
+                    WSMessage::Closed { session } => {
+                        let throttle = match ws_session_throttles.lock().unwrap().remove(&session) {
+                            Some(throttle) => throttle,
+                            None => continue,
+                        };
+                        ws_session_consists.lock().unwrap().remove(&session);
+                        dead_man(
+                            ws_dead_man,
+                            session,
+                            throttle,
+                            ws_owners.clone(),
+                            ws_throttles.clone(),
+                            ws_status.clone(),
+                            ws_jmri_sender.clone(),
+                        );
+                        continue;
+                    }
+                    _ => continue,
                 },
+                None => break,
             };
 
-            // If client requests, send entire current Throttle struct
-            if msg == "update" {
-                let throttle = ws_throttles.lock().unwrap();
-                let throttle = throttle.get(ADDR).unwrap();
-                let message = serde_json::to_string(throttle).unwrap();
-                let send = WSMessage::Send {
-                    address: ADDR.to_string(),
-                    message,
-                };
-                ws_chann_tx.send(send).unwrap();
+            let request = match serde_json::from_str::<ClientRequest>(msg.as_str()) {
+                Ok(request) => request,
+                Err(e) => {
+                    let error = RequestError::Parse {
+                        message: e.to_string(),
+                    };
+                    reject(&ws_chann_tx, session, None, error);
+                    continue;
+                }
+            };
+            let id = request.id();
+
+            let mut update = match request {
+                ClientRequest::Command { command, .. } => JmriUpdate::from(command),
+                ClientRequest::Query { query, .. } => {
+                    let result = match query {
+                        Query::Throttles => {
+                            let throttles = ws_throttles.lock().unwrap();
+                            let owners = ws_owners.lock().unwrap();
+                            let owned = throttles
+                                .values()
+                                .filter(|throttle| {
+                                    owners.get(throttle.get_address()) == Some(&session)
+                                })
+                                .cloned()
+                                .collect();
+                            Some(QueryResult::Throttles(owned))
+                        }
+                        Query::Roster => {
+                            let roster = ws_roster.lock().unwrap().clone();
+                            Some(QueryResult::Update(JmriUpdate::Roster(roster)))
+                        }
+                        Query::Consists => {
+                            let consists = ws_consists.lock().unwrap().values().cloned().collect();
+                            Some(QueryResult::Update(JmriUpdate::Consists(consists)))
+                        }
+                        Query::Turnouts => {
+                            let turnouts = ws_turnouts.lock().unwrap().values().cloned().collect();
+                            Some(QueryResult::Update(JmriUpdate::Turnouts(turnouts)))
+                        }
+                        Query::TurnoutTitles => {
+                            let titles = ws_turnout_titles.lock().unwrap().clone();
+                            Some(QueryResult::Update(JmriUpdate::TurnoutTitles(titles)))
+                        }
+                        Query::Routes => {
+                            let routes = ws_routes.lock().unwrap().values().cloned().collect();
+                            Some(QueryResult::Update(JmriUpdate::Routes(routes)))
+                        }
+                        Query::Power => {
+                            let power = *ws_power.lock().unwrap();
+                            Some(QueryResult::Update(JmriUpdate::Power(power)))
+                        }
+                        Query::Status => {
+                            let status = ws_status.lock().unwrap().clone();
+                            Some(QueryResult::Status(status))
+                        }
+                        Query::Time => match ws_time.lock().unwrap().as_ref() {
+                            Some(time) => Some(QueryResult::Update(JmriUpdate::Time {
+                                timestamp: time.now(),
+                                scale: time.scale,
+                            })),
+                            None => {
+                                let message = "JMRI hasn't reported a fast clock".to_string();
+                                let error = RequestError::InvalidValue { message };
+                                reject(&ws_chann_tx, session, Some(id), error);
+                                continue;
+                            }
+                        },
+                    };
+                    reply(&ws_chann_tx, session, &ServerEvent::Success { id, result });
+                    continue;
+                }
+                // Emergency stop every locomotive the session owns, or with `all`,
+                // every locomotive on the layout
+                ClientRequest::EmergencyStop { all, .. } => {
+                    if !ws_status.lock().unwrap().jmri_online {
+                        reject(&ws_chann_tx, session, Some(id), RequestError::JmriOffline);
+                        continue;
+                    }
+                    let throttles = ws_throttles.lock().unwrap();
+                    let owners = ws_owners.lock().unwrap();
+                    let stopping = throttles.values().filter(|throttle| {
+                        all || owners.get(throttle.get_address()) == Some(&session)
+                    });
+                    let stops = stopping
+                        .filter_map(|throttle| {
+                            make_jmri_request(JmriUpdate::EmergencyStop {
+                                throttle: throttle.get_throttle(),
+                                address: throttle.get_address().to_string(),
+                            })
+                        })
+                        .collect();
+                    if let Some(request) = batch(stops) {
+                        let _ = ws_jmri_sender.send(request);
+                    }
+                    reply(
+                        &ws_chann_tx,
+                        session,
+                        &ServerEvent::Success { id, result: None },
+                    );
+                    continue;
+                }
+                ClientRequest::Ping { .. } => {
+                    reply(
+                        &ws_chann_tx,
+                        session,
+                        &ServerEvent::Success { id, result: None },
+                    );
+                    continue;
+                }
+                ClientRequest::Subscribe { address, .. } => {
+                    if !parse::is_address(&address) {
+                        let error = RequestError::InvalidAddress { address };
+                        reject(&ws_chann_tx, session, Some(id), error);
+                        continue;
+                    }
+                    subscribe(&ws_subscriptions, session, &address);
+                    reply(
+                        &ws_chann_tx,
+                        session,
+                        &ServerEvent::Success { id, result: None },
+                    );
+                    continue;
+                }
+                ClientRequest::Unsubscribe { address, .. } => {
+                    if ws_owners.lock().unwrap().get(&address) == Some(&session) {
+                        let message =
+                            format!("{} is owned by this session, release it instead", address);
+                        let error = RequestError::InvalidValue { message };
+                        reject(&ws_chann_tx, session, Some(id), error);
+                        continue;
+                    }
+                    unsubscribe(&ws_subscriptions, session, &address);
+                    reply(
+                        &ws_chann_tx,
+                        session,
+                        &ServerEvent::Success { id, result: None },
+                    );
+                    continue;
+                }
+            };
+
+            // Ad-hoc consists are kept by the bridge, everything else has to reach JMRI
+            let local = matches!(
+                update,
+                JmriUpdate::ConsistAdd { .. } | JmriUpdate::ConsistRemove { .. }
+            );
+            if !local && !ws_status.lock().unwrap().jmri_online {
+                reject(&ws_chann_tx, session, Some(id), RequestError::JmriOffline);
                 continue;
             }
 
-            // For testing Serde serialization on the Update messages
-            if msg == "test-update" {
-                let update_func = JmriUpdate::Function {
-                    is_on: true,
-                    num: 12,
+            // Names go into JMRI's command verbatim, so only those JMRI listed are accepted
+            if let JmriUpdate::Turnout { name, .. } | JmriUpdate::ToggleTurnout { name } = &update {
+                if !ws_turnouts.lock().unwrap().contains_key(name) {
+                    let message = format!("unknown turnout '{}'", name);
+                    let error = RequestError::InvalidValue { message };
+                    reject(&ws_chann_tx, session, Some(id), error);
+                    continue;
+                }
+            }
+            if let JmriUpdate::Route { name, .. } = &update {
+                if !ws_routes.lock().unwrap().contains_key(name) {
+                    let message = format!("unknown route '{}'", name);
+                    let error = RequestError::InvalidValue { message };
+                    reject(&ws_chann_tx, session, Some(id), error);
+                    continue;
+                }
+            }
+
+            // JMRI takes -1 to emergency stop, otherwise 0 up to the maximum velocity
+            let invalid = match update {
+                JmriUpdate::Velocity { value, .. }
+                    if !(-1..=dcc::MAX_VELOCITY).contains(&value) =>
+                {
+                    Some(format!(
+                        "velocity {} is out of range, velocities go from -1 to {}",
+                        value,
+                        dcc::MAX_VELOCITY
+                    ))
+                }
+                JmriUpdate::Speed { speed, .. } if !speed.is_finite() => {
+                    Some(format!("speed {} is not a number", speed))
+                }
+                _ => None,
+            };
+            if let Some(message) = invalid {
+                let error = RequestError::InvalidValue { message };
+                reject(&ws_chann_tx, session, Some(id), error);
+                continue;
+            }
+
+            let mut takeover = None;
+            if let Some(address) = update.address() {
+                if !parse::is_address(address) {
+                    let error = RequestError::InvalidAddress {
+                        address: address.to_string(),
+                    };
+                    reject(&ws_chann_tx, session, Some(id), error);
+                    continue;
+                }
+
+                if let Some(Err(e)) = update.function().map(dcc::check_function) {
+                    reject(&ws_chann_tx, session, Some(id), e.into());
+                    continue;
+                }
+
+                let (held_by, in_use) = {
+                    let throttles = ws_throttles.lock().unwrap();
+                    let in_use: HashSet<ThrottleId> =
+                        throttles.values().map(Throttle::get_throttle).collect();
+                    (throttles.get(address).map(Throttle::get_throttle), in_use)
                 };
-                let update_func = serde_json::to_string(&update_func).unwrap();
-                let update_func = WSMessage::Send {
-                    address: ADDR.to_string(),
-                    message: update_func,
+                let mut owners = ws_owners.lock().unwrap();
+                match (owners.get(address), &update) {
+                    (Some(owner), _) if *owner != session => {
+                        let error = RequestError::NotOwned {
+                            address: address.to_string(),
+                            owner: *owner,
+                        };
+                        reject(&ws_chann_tx, session, Some(id), error);
+                        continue;
+                    }
+                    (None, JmriUpdate::Acquire { .. }) => {}
+                    (None, _) => {
+                        let error = RequestError::UnknownAddress {
+                            address: address.to_string(),
+                        };
+                        reject(&ws_chann_tx, session, Some(id), error);
+                        continue;
+                    }
+                    // JMRI never handed the loco over, e.g. the session declined a steal
+                    (Some(_), JmriUpdate::Release { .. }) if held_by.is_none() => {
+                        owners.remove(address);
+                        unsubscribe(&ws_subscriptions, session, address);
+                        reply(
+                            &ws_chann_tx,
+                            session,
+                            &ServerEvent::Success { id, result: None },
+                        );
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                // Only a request that will reach JMRI takes one of the few throttle ids
+                let throttle = {
+                    let mut session_throttles = ws_session_throttles.lock().unwrap();
+                    match session_throttle(&mut session_throttles, session, &in_use) {
+                        Some(throttle) => throttle,
+                        None => {
+                            let message = "no free throttle ids left".to_string();
+                            let error = RequestError::InvalidValue { message };
+                            reject(&ws_chann_tx, session, Some(id), error);
+                            continue;
+                        }
+                    }
                 };
 
-                let update_dir = JmriUpdate::Direction(Direction::Forward);
-                let update_dir = serde_json::to_string(&update_dir).unwrap();
-                let update_dir = WSMessage::Send {
-                    address: ADDR.to_string(),
-                    message: update_dir,
+                if !owners.contains_key(address) {
+                    owners.insert(address.to_string(), session);
+                    subscribe(&ws_subscriptions, session, address);
+                    // A closed session left it stopped on its own throttle, so free it there first
+                    takeover = held_by.filter(|held| *held != throttle);
+                }
+
+                update.set_throttle(throttle);
+            }
+
+            // Ad-hoc consists live in the bridge, JMRI only sees the individual locomotives
+            if let JmriUpdate::ConsistAdd { .. } | JmriUpdate::ConsistRemove { .. } = update {
+                let mut session_consists = ws_session_consists.lock().unwrap();
+                let consist = match update {
+                    JmriUpdate::ConsistAdd {
+                        address, reversed, ..
+                    } => {
+                        let consist = session_consists
+                            .entry(session)
+                            .or_insert_with(|| Consist::new(address.clone(), String::new()));
+                        consist.add(address, reversed);
+                        consist.clone()
+                    }
+                    JmriUpdate::ConsistRemove { address, .. } => {
+                        let consist = match session_consists.get_mut(&session) {
+                            Some(consist) => consist,
+                            None => {
+                                let message = "no ad-hoc consist to remove from".to_string();
+                                let error = RequestError::InvalidValue { message };
+                                reject(&ws_chann_tx, session, Some(id), error);
+                                continue;
+                            }
+                        };
+                        consist.remove(&address);
+                        let consist = consist.clone();
+                        if consist.is_empty() {
+                            session_consists.remove(&session);
+                        }
+                        consist
+                    }
+                    _ => continue,
                 };
 
-                let update_vel = JmriUpdate::Velocity(20);
-                let update_vel = serde_json::to_string(&update_vel).unwrap();
-                let update_vel = WSMessage::Send {
-                    address: ADDR.to_string(),
-                    message: update_vel,
+                let result = Some(QueryResult::Update(JmriUpdate::Consist(consist)));
+                reply(&ws_chann_tx, session, &ServerEvent::Success { id, result });
+                continue;
+            }
+
+            // Normalized speeds are sent as the velocity for the decoder's speed steps
+            if let JmriUpdate::Speed {
+                throttle,
+                address,
+                speed,
+            } = update
+            {
+                let mode = ws_throttles
+                    .lock()
+                    .unwrap()
+                    .get(&address)
+                    .map(|throttle| throttle.get_speed_steps())
+                    .unwrap_or_default();
+                update = JmriUpdate::Velocity {
+                    throttle,
+                    address,
+                    value: mode.to_velocity(speed),
+                    speed: None,
                 };
+            }
 
-                ws_chann_tx.send(update_func).unwrap();
-                ws_chann_tx.send(update_dir).unwrap();
-                ws_chann_tx.send(update_vel).unwrap();
+            let mut updates = match update {
+                JmriUpdate::FunctionButton { .. } => {
+                    let throttles = ws_throttles.lock().unwrap();
+                    function_button(&throttles, update)
+                }
+                JmriUpdate::Velocity { .. }
+                | JmriUpdate::Direction { .. }
+                | JmriUpdate::EmergencyStop { .. }
+                | JmriUpdate::Idle { .. } => {
+                    match ws_session_consists.lock().unwrap().get(&session) {
+                        Some(consist) => consist_updates(consist, update),
+                        None => vec![update],
+                    }
+                }
+                update => vec![update],
+            };
+
+            if let (Some(throttle), Some(JmriUpdate::Acquire { address, .. })) =
+                (takeover, updates.first())
+            {
+                let address = address.clone();
+                updates.insert(0, JmriUpdate::Release { throttle, address });
+            }
+
+            // A latching button's release legitimately sends nothing
+            let expected = !updates.is_empty();
+            let requests: Vec<JmriMessage> =
+                updates.into_iter().filter_map(make_jmri_request).collect();
+            if expected && requests.is_empty() {
+                let message = "not a command JMRI accepts".to_string();
+                let error = RequestError::InvalidValue { message };
+                reject(&ws_chann_tx, session, Some(id), error);
                 continue;
             }
 
-            if let Ok(msg) = serde_json::from_str::<JmriUpdate>(msg.as_str()) {
-                if let Some(request) = make_jmri_request(ADDR, msg) {
-                    ws_jmri_sender.send(request).unwrap();
+            if let Some(request) = batch(requests) {
+                let _ = ws_jmri_sender.send(request);
+            }
+            reply(
+                &ws_chann_tx,
+                session,
+                &ServerEvent::Success { id, result: None },
+            );
+        }
+    });
+
+    // Every task runs until shutdown, so any of them finishing early is fatal
+    let result: Result<(), Box<dyn Error>> = tokio::select! {
+        _ = shutdown_signal() => Ok(()),
+        reason = jmri_stream.finished() => Err(format!("JMRI stream stopped: {}", reason).into()),
+        result = ws_listener.finished() => Err(task_error("WebSocket server", result)),
+        result = &mut jmri_listen_handle => Err(task_error("JMRI listener", result)),
+        result = &mut clock_handle => Err(task_error("fast clock", result)),
+        result = &mut version_handle => Err(task_error("version check", result)),
+        result = &mut ws_listen_handle => Err(task_error("WebSocket listener", result)),
+    };
+
+    match &result {
+        Ok(()) => info!("Shutting down"),
+        Err(e) => error!("Shutting down: {}", e),
+    }
+    if status.lock().unwrap().jmri_online {
+        release_all(&throttles, &jmri_sender).await;
+    }
+
+    result
+}
+
+/// Stops every locomotive a closed session was driving, then releases them once the
+/// policy's grace period is up, so nothing runs away when a client drops off. If the policy
+/// keeps them, they are disowned right away so another session can take them over.
+fn dead_man(
+    policy: DeadManPolicy,
+    session: SessionId,
+    throttle: ThrottleId,
+    owners: OwnersState,
+    throttles: ThrottlesState,
+    status: StatusState,
+    jmri_sender: JmriSender,
+) {
+    let addresses: Vec<String> = owners
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, owner)| **owner == session)
+        .map(|(address, _)| address.clone())
+        .collect();
+
+    let mut stops = Vec::with_capacity(addresses.len());
+    for address in &addresses {
+        info!("Session {} closed, stopping {}", session, address);
+        // Record the stop ourselves in case JMRI is offline and never confirms it
+        if let Some(throttle) = throttles.lock().unwrap().get_mut(address) {
+            throttle.set_vel(0);
+        }
+        let address = address.clone();
+        let stop = match policy.action {
+            DeadManAction::Stop => JmriUpdate::Velocity {
+                throttle,
+                address,
+                value: 0,
+                speed: None,
+            },
+            DeadManAction::EmergencyStop => JmriUpdate::EmergencyStop { throttle, address },
+        };
+        stops.extend(make_jmri_request(stop));
+    }
+    if let Some(request) = batch(stops) {
+        let _ = jmri_sender.send(request);
+    }
+
+    let release_after = match policy.release_after {
+        Some(release_after) => release_after,
+        None => {
+            // Left stopped on the session's throttle for any other session to take over
+            let mut owners = owners.lock().unwrap();
+            for address in &addresses {
+                if owners.get(address) == Some(&session) {
+                    owners.remove(address);
+                }
+            }
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        tokio::time::sleep(release_after).await;
+
+        let mut released = addresses;
+        {
+            let mut owners = owners.lock().unwrap();
+            released.retain(|address| {
+                // The listener may already have dropped it, e.g. if JMRI released it meanwhile
+                if owners.get(address) != Some(&session) {
+                    return false;
                 }
+                owners.remove(address);
+                true
+            });
+        }
+
+        // JMRI won't confirm releases while offline, so forget the throttles now
+        if !status.lock().unwrap().jmri_online {
+            let mut throttles = throttles.lock().unwrap();
+            for address in &released {
+                throttles.remove(address);
             }
         }
+
+        let releases = released
+            .into_iter()
+            .filter_map(|address| {
+                info!("Releasing {} from closed session {}", address, session);
+                make_jmri_request(JmriUpdate::Release { throttle, address })
+            })
+            .collect();
+        if let Some(request) = batch(releases) {
+            let _ = jmri_sender.send(request);
+        }
     });
+}
 
-    // TODO: figure out how to fall-through if either exit, handling errors
-    jmri_listen_handle.await?;
-    ws_listen_handle.await?;
+/// Resolves once the process is asked to stop with Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-    Ok(())
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => warn!("Unable to listen for SIGTERM: {}", e),
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
 }
 
-fn make_jmri_request(address: &str, update: JmriUpdate) -> Option<JmriMessage> {
+fn task_error(task: &str, result: Result<(), JoinError>) -> Box<dyn Error> {
+    match result {
+        Ok(()) => format!("{} stopped", task).into(),
+        Err(e) => format!("{} failed: {}", task, e).into(),
+    }
+}
+
+/// How long to wait for JMRI to confirm every release before exiting anyway.
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Stops and releases every acquired locomotive so nothing keeps running after we exit.
+async fn release_all(throttles: &ThrottlesState, jmri_sender: &JmriSender) {
+    let requests = throttles
+        .lock()
+        .unwrap()
+        .values()
+        .flat_map(|throttle| {
+            let throttle_id = throttle.get_throttle();
+            let address = throttle.get_address().to_string();
+            info!("Releasing {}", address);
+            [
+                JmriUpdate::Velocity {
+                    throttle: throttle_id,
+                    address: address.clone(),
+                    value: 0,
+                    speed: None,
+                },
+                JmriUpdate::Release {
+                    throttle: throttle_id,
+                    address,
+                },
+            ]
+        })
+        .filter_map(make_jmri_request)
+        .collect();
+    if let Some(request) = batch(requests) {
+        let _ = jmri_sender.send(request);
+    }
+
+    // The listener forgets each throttle once JMRI confirms its release
+    let deadline = Instant::now() + RELEASE_TIMEOUT;
+    while !throttles.lock().unwrap().is_empty() {
+        if Instant::now() >= deadline {
+            warn!("JMRI didn't confirm every release before shutting down");
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Sends `event` to a single session.
+fn reply(sender: &broadcast::Sender<WSMessage>, session: SessionId, event: &ServerEvent) {
+    let message = serde_json::to_string(event).unwrap();
+    let _ = sender.send(WSMessage::Reply { session, message });
+}
+
+/// Logs why a session's request was refused and tells the session.
+fn reject(
+    sender: &broadcast::Sender<WSMessage>,
+    session: SessionId,
+    id: Option<RequestId>,
+    error: RequestError,
+) {
+    warn!("Refused request from session {}: {}", session, error);
+    let message = error.to_string();
+    reply(sender, session, &ServerEvent::Error { id, error, message });
+}
+
+/// Turns a client's button press or release into the presses and releases JMRI needs.
+/// Momentary functions follow the button. Latching functions toggle on each press, so a
+/// press is sent as a full press and release and the client's own release is dropped.
+fn function_button(throttles: &HashMap<String, Throttle>, update: JmriUpdate) -> Vec<JmriUpdate> {
+    let (throttle, address, num, pressed) = match update {
+        JmriUpdate::FunctionButton {
+            throttle,
+            address,
+            num,
+            pressed,
+        } => (throttle, address, num, pressed),
+        update => return vec![update],
+    };
+
+    let momentary = throttles
+        .get(&address)
+        .map(|t| t.is_momentary(num))
+        .unwrap_or(false);
+    let button = |pressed| JmriUpdate::FunctionButton {
+        throttle,
+        address: address.clone(),
+        num,
+        pressed,
+    };
+
+    match (momentary, pressed) {
+        (true, pressed) => vec![button(pressed)],
+        (false, true) => vec![button(true), button(false)],
+        (false, false) => Vec::new(),
+    }
+}
+
+/// Repeats a movement command for a locomotive in `consist` to every member,
+/// flipping the direction for members facing the other way.
+fn consist_updates(consist: &Consist, update: JmriUpdate) -> Vec<JmriUpdate> {
+    let reversed = match update.address().and_then(|address| consist.member(address)) {
+        Some(member) => member.reversed,
+        None => return vec![update],
+    };
+
+    consist
+        .members
+        .iter()
+        .map(|member| {
+            let mut update = update.clone();
+            update.set_address(member.address.clone());
+            if let JmriUpdate::Direction { direction, .. } = &mut update {
+                if member.reversed != reversed {
+                    *direction = direction.reversed();
+                }
+            }
+            update
+        })
+        .collect()
+}
+
+/// Sends the session every update for `address` from now on.
+fn subscribe(subscriptions: &Subscriptions, session: SessionId, address: &str) {
+    let mut subscriptions = subscriptions.lock().unwrap();
+    subscriptions
+        .entry(session)
+        .or_default()
+        .insert(address.to_string());
+}
+
+fn unsubscribe(subscriptions: &Subscriptions, session: SessionId, address: &str) {
+    if let Some(addresses) = subscriptions.lock().unwrap().get_mut(&session) {
+        addresses.remove(address);
+    }
+}
+
+/// Joins several requests into one message so none of them can be dropped by a lagging
+/// channel on the way to JMRI.
+fn batch(requests: Vec<JmriMessage>) -> Option<JmriMessage> {
+    let lines: Vec<String> = requests
+        .into_iter()
+        .filter_map(|request| match request {
+            JmriMessage::Send(line) => Some(line),
+            _ => None,
+        })
+        .collect();
+
+    if lines.is_empty() {
+        None
+    } else {
+        Some(JmriMessage::Send(lines.join(RETURN)))
+    }
+}
+
+/// Re-acquires a throttle after a reconnection and restores its speed, direction and functions.
+fn replay_throttle(throttle: &Throttle) -> Vec<JmriMessage> {
+    let id = throttle.get_throttle();
+    let address = throttle.get_address();
+
+    let mut messages = vec![
+        JmriMessage::throttle(id, '+', address, address),
+        JmriMessage::throttle(id, 'A', address, &throttle.get_dir().to_string()),
+        JmriMessage::throttle(id, 'A', address, &format!("V{}", throttle.get_vel().max(0))),
+    ];
+    // Force functions on rather than toggling them, as JMRI may have kept their state
+    for num in throttle.get_funcs() {
+        messages.push(JmriMessage::throttle(
+            id,
+            'A',
+            address,
+            &format!("f1{}", num),
+        ));
+    }
+
+    messages.into_iter().map(JmriMessage::Send).collect()
+}
+
+/// Returns the session's throttle id, assigning it the first free one if it has none yet.
+fn session_throttle(
+    session_throttles: &mut HashMap<SessionId, ThrottleId>,
+    session: SessionId,
+    in_use: &HashSet<ThrottleId>,
+) -> Option<ThrottleId> {
+    if let Some(throttle) = session_throttles.get(&session) {
+        return Some(*throttle);
+    }
+
+    // A closed session's id stays taken while JMRI still holds locomotives on it, so late
+    // confirmations for them can't be mistaken for the next session's
+    let throttle = ThrottleId::pool()
+        .find(|id| !in_use.contains(id) && !session_throttles.values().any(|t| t == id))?;
+    session_throttles.insert(session, throttle);
+    Some(throttle)
+}
+
+fn make_jmri_request(update: JmriUpdate) -> Option<JmriMessage> {
+    if let Some(address) = update.address() {
+        if !parse::is_address(address) {
+            warn!("Ignoring request for invalid address '{}'", address);
+            return None;
+        }
+    }
+
+    if let Some(Err(e)) = update.function().map(dcc::check_function) {
+        warn!("Ignoring request for invalid function: {}", e);
+        return None;
+    }
+
     let msg = match update {
-        JmriUpdate::Function { num, is_on } => {
+        JmriUpdate::Acquire { throttle, address } => {
+            JmriMessage::Send(JmriMessage::throttle(throttle, '+', &address, &address))
+        }
+        JmriUpdate::Release { throttle, address } => {
+            JmriMessage::Send(JmriMessage::throttle(throttle, '-', &address, "r"))
+        }
+        JmriUpdate::Function {
+            throttle,
+            address,
+            num,
+            is_on,
+        } => {
             let is_on = if is_on { "1" } else { "0" };
-            JmriMessage::Send(format!("MTA{}<;>F{}{}", address, is_on, num))
+            let command = format!("f{}{}", is_on, num);
+            JmriMessage::Send(JmriMessage::throttle(throttle, 'A', &address, &command))
+        }
+        JmriUpdate::FunctionButton {
+            throttle,
+            address,
+            num,
+            pressed,
+        } => {
+            let pressed = if pressed { "1" } else { "0" };
+            let command = format!("F{}{}", pressed, num);
+            JmriMessage::Send(JmriMessage::throttle(throttle, 'A', &address, &command))
+        }
+        JmriUpdate::FunctionMode {
+            throttle,
+            address,
+            num,
+            momentary,
+        } => {
+            let momentary = if momentary { "1" } else { "0" };
+            let command = format!("m{}{}", momentary, num);
+            JmriMessage::Send(JmriMessage::throttle(throttle, 'A', &address, &command))
+        }
+        JmriUpdate::Velocity {
+            throttle,
+            address,
+            value,
+            ..
+        } => {
+            let s = JmriMessage::throttle(throttle, 'A', &address, &format!("V{}", value));
+            let q = JmriMessage::throttle(throttle, 'A', &address, "qV");
+            JmriMessage::Send(format!("{}\n{}", s, q))
+        }
+        JmriUpdate::Direction {
+            throttle,
+            address,
+            direction,
+        } => {
+            let s = JmriMessage::throttle(throttle, 'A', &address, &direction.to_string());
+            let q = JmriMessage::throttle(throttle, 'A', &address, "vR");
+            JmriMessage::Send(format!("{}\n{}", s, q))
+        }
+        JmriUpdate::SpeedSteps {
+            throttle,
+            address,
+            mode,
+        } => JmriMessage::Send(JmriMessage::throttle(
+            throttle,
+            'A',
+            &address,
+            &mode.to_string(),
+        )),
+        JmriUpdate::Steal { throttle, address } => {
+            JmriMessage::Send(JmriMessage::throttle(throttle, 'S', &address, &address))
         }
-        JmriUpdate::Velocity(vel) => {
-            let s = format!("MTA{}<;>V{}", address, vel);
-            JmriMessage::Send(format!("{}\nMTA{}<;>qV", s, address))
+        JmriUpdate::EmergencyStop { throttle, address } => {
+            let s = JmriMessage::throttle(throttle, 'A', &address, "X");
+            let q = JmriMessage::throttle(throttle, 'A', &address, "qV");
+            JmriMessage::Send(format!("{}\n{}", s, q))
         }
-        JmriUpdate::Direction(dir) => {
-            let s = format!("MTA{}<;>{}", address, dir);
-            JmriMessage::Send(format!("{}\nMTA{}<;>vR", s, address))
+        JmriUpdate::Idle { throttle, address } => {
+            let s = JmriMessage::throttle(throttle, 'A', &address, "I");
+            let q = JmriMessage::throttle(throttle, 'A', &address, "qV");
+            JmriMessage::Send(format!("{}\n{}", s, q))
         }
+        JmriUpdate::Turnout { name, state } => match state {
+            TurnoutState::Closed => JmriMessage::Send(format!("PTAC{}", name)),
+            TurnoutState::Thrown => JmriMessage::Send(format!("PTAT{}", name)),
+            _ => return None,
+        },
+        JmriUpdate::ToggleTurnout { name } => JmriMessage::Send(format!("PTA2{}", name)),
+        JmriUpdate::Route {
+            name,
+            state: RouteState::Active,
+        } => JmriMessage::Send(format!("PRA2{}", name)),
+        JmriUpdate::Power(state) => match state {
+            PowerState::Off => JmriMessage::Send("PPA0".to_string()),
+            PowerState::On => JmriMessage::Send("PPA1".to_string()),
+            PowerState::Unknown => return None,
+        },
         _ => return None,
     };
 